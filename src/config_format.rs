@@ -0,0 +1,157 @@
+//! TOML and YAML config files, converted to and from the JSON value the config
+//! is (de)serialized through, so `Config` stays the only description of the
+//! fields.
+
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl std::fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ConfigFormat::Json => "JSON",
+            ConfigFormat::Toml => "TOML",
+            ConfigFormat::Yaml => "YAML",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ConfigFormat {
+    /// The format of a config file by its extension, JSON unless `.toml`,
+    /// `.yaml` or `.yml`.
+    pub fn from_path(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    pub fn parse(self, content: &str) -> Result<Value, String> {
+        match self {
+            ConfigFormat::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+            // a file of only comments is an empty config, as in TOML
+            ConfigFormat::Yaml => match serde_yaml::from_str(content) {
+                Ok(Value::Null) => Ok(Value::Object(Map::new())),
+                result => result.map_err(|e| e.to_string()),
+            },
+        }
+    }
+
+    /// Write `value`, an object, in the format. The JSON is pretty printed,
+    /// and TOML, which has no null, leaves out the null fields.
+    pub fn write(self, value: &Value) -> Result<String, String> {
+        match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => {
+                toml::to_string_pretty(&without_nulls(value)).map_err(|e| e.to_string())
+            }
+            ConfigFormat::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// `value` without the null fields of its objects, at any depth.
+fn without_nulls(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k.clone(), without_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.iter().map(without_nulls).collect()),
+        v => v.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config() -> Value {
+        json!({
+            "core_count": 2,
+            "memory_overhead": 0.5,
+            "shell": true,
+            "set_envs": ["A=1", "B=\"two words\""],
+            "pending_reservation": null,
+            "presets": {"big": {"core_count": 4, "gpu_env": "HIP_VISIBLE_DEVICES"}},
+            "memory_per_core_by_count": {},
+            "schedule": [{"days": "mon-fri", "hours": "9-18"}]
+        })
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let content = ConfigFormat::Toml.write(&config()).unwrap();
+        let mut expected = config();
+        expected
+            .as_object_mut()
+            .unwrap()
+            .remove("pending_reservation");
+        assert_eq!(ConfigFormat::Toml.parse(&content).unwrap(), expected);
+    }
+
+    #[test]
+    fn yaml_round_trip() {
+        let content = ConfigFormat::Yaml.write(&config()).unwrap();
+        assert_eq!(ConfigFormat::Yaml.parse(&content).unwrap(), config());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let content = ConfigFormat::Json.write(&config()).unwrap();
+        assert_eq!(ConfigFormat::Json.parse(&content).unwrap(), config());
+    }
+
+    #[test]
+    fn empty_yaml_is_empty_object() {
+        assert_eq!(
+            ConfigFormat::Yaml.parse("# nothing set\n").unwrap(),
+            json!({})
+        );
+    }
+
+    #[test]
+    fn invalid_content_is_err() {
+        assert!(ConfigFormat::Toml.parse("core_count = [").is_err());
+        assert!(ConfigFormat::Yaml.parse("core_count: [1").is_err());
+        assert!(ConfigFormat::Json.parse("{").is_err());
+    }
+
+    #[test]
+    fn null_in_array_is_err_in_toml() {
+        assert!(ConfigFormat::Toml.write(&json!({"a": [null]})).is_err());
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.toml")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.yml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.yaml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new(".plan.json")),
+            ConfigFormat::Json
+        );
+    }
+}
@@ -1,349 +1,8169 @@
+use clap::Args;
 use clap::CommandFactory;
 use clap::Parser;
+use clap::Subcommand;
+use clap::ValueEnum;
+use config_format::ConfigFormat;
 use env_logger::Builder;
-use log::{LevelFilter, info, warn};
+use log::{LevelFilter, error, info, warn};
 use serde::{Deserialize, Serialize};
-use serde_json;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
 use std::fmt::Debug;
 use std::fs;
 use std::io::{Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::process::exit;
+use std::process::{Command, ExitStatus};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, mpsc};
+use std::thread;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+// nvidia-smi, the process groups, the signals, the sockets and the lock files
+// all rely on Unix
+#[cfg(not(unix))]
+compile_error!("gpu_wizard_execute only supports Unix");
+
+mod config_format;
+#[cfg(feature = "notify")]
+mod notify;
+#[cfg(feature = "nvml")]
+mod nvml;
+#[cfg(feature = "otel")]
+mod otel;
+
+#[derive(Serialize, Clone, Default)]
 struct GPUInfo {
     gpu_free: usize,
     index: usize,
-    memory_free: u32, // GiB
+    memory_free: u32,  // MiB
+    memory_total: u32, // MiB
+    /// In MiB, as reported besides `memory_free`, which it doesn't always
+    /// add up with (i.e. under MPS).
+    memory_used: Option<u32>,
+    uuid: String,
+    /// Whether the driver stays loaded without clients, avoiding the init
+    /// latency on first use.
+    persistence_mode: bool,
+    /// Uncorrected ECC errors since the driver loaded, `None` where ECC is
+    /// off or unsupported.
+    ecc_errors: Option<u64>,
+    /// In degrees Celsius.
+    temperature: Option<u32>,
+    /// The power limit and the current draw in watts, `None` where the GPU
+    /// doesn't report them.
+    power_limit: Option<f64>,
+    power_draw: Option<f64>,
+    /// How much `temperature` rose over the last checks, only tracked while
+    /// waiting with `max_temp_delta`.
+    temperature_rise: Option<u32>,
+    processes: Vec<GPUProcess>,
+    /// The lowest index of the GPUs behind the same PCIe root complex, only
+    /// queried with `spread_pcie`.
+    pcie_root: Option<usize>,
+    /// When a command of this tool last used the GPU, in seconds since the
+    /// epoch, only read with a usage ledger.
+    last_used: Option<u64>,
+    /// The values of the fields of `filter_fields` as queried, in their order.
+    field_values: Vec<String>,
+}
+
+/// A compute process running on a GPU.
+#[derive(Serialize, Debug, Clone)]
+struct GPUProcess {
+    pid: u32,
+    user: Option<String>,
 }
 
 impl Debug for GPUInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
+        writeln!(
             f,
-            "GPUInfo {{index: {}, memory_free: {} G, gpu_free: {} %}}\n",
-            self.index, self.memory_free, self.gpu_free
+            "GPUInfo {{index: {}, memory_free: {}/{} MiB, gpu_free: {} %}}",
+            self.index, self.memory_free, self.memory_total, self.gpu_free
         )
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Fields missing from a config file take their value from `default_config`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default = "default_config")]
 struct Config {
+    /// The version of the config format, 1 if missing, see [`CONFIG_VERSION`].
+    config_version: u64,
     core_count: usize,
+    /// Wait for exactly these GPUs instead of any `core_count` of them.
+    wait_for_indices: Vec<usize>,
+    /// Only select among these GPUs, all if empty.
+    allow_gpus: Vec<usize>,
+    /// Never select these GPUs.
+    deny_gpus: Vec<usize>,
+    /// A file listing the GPUs a pending larger job waits for, left to it.
+    pending_reservation: Option<PathBuf>,
     memory_per_core: u32,
+    /// The free memory each GPU needs as a percentage of its total memory,
+    /// replacing `memory_per_core` when set.
+    memory_percent: Option<usize>,
+    /// GiB of each GPU taken by the driver and display, subtracted from the
+    /// total memory before the percentages of it are taken.
+    memory_overhead: f64,
     gpu_percent: usize,
     check_times: usize,
     check_interval: u64,
+    /// Up to this many seconds, uniformly random, added to each sleep between
+    /// checks, so that instances started together don't poll in lockstep.
+    jitter: f64,
+    /// Seconds the interval doubles up to with each further check in a row
+    /// the resource isn't enough, 0 to keep it at `check_interval`.
+    backoff_max: u64,
+    /// Seconds, 0 to wait indefinitely.
+    max_wait: u64,
     gpu_env: String,
+    /// Warn if the value of `gpu_env` is longer, for tools limiting it.
+    gpu_env_max_len: Option<usize>,
+    /// Warn if more GPUs are selected.
+    gpu_env_max_count: Option<usize>,
+    /// `NAME=value` entries, in whose values `$VAR` and `${VAR}` are expanded.
     set_envs: Vec<String>,
+    /// Fail on a reference to an undefined variable in `set_envs`, instead of
+    /// expanding it to nothing.
+    strict_env_expansion: bool,
     unset_envs: Vec<String>,
+    /// `memory_per_core` by `core_count`, see [`memory_for_core_count`].
+    memory_per_core_by_count: BTreeMap<usize, u32>,
+    min_driver_version: Option<String>,
+    strategy: Strategy,
+    persistence_mode: PersistenceMode,
+    /// Which tool reports the GPUs.
+    backend: Backend,
+    /// Read the local NVIDIA GPUs through NVML, falling back to nvidia-smi.
+    #[cfg(feature = "nvml")]
+    nvml: bool,
+    near_miss_percent: usize,
+    show_eta: bool,
+    notify_command: Option<String>,
+    /// Allow GPUs that already run a process of the current user, the default.
+    /// Off, the processes are queried on every check to skip these GPUs.
+    allow_self_stacking: bool,
+    /// Only select GPUs without any compute process.
+    require_exclusive: bool,
+    spread_pcie: bool,
+    skip_ecc_errors: bool,
+    /// The watts the power limits of the selected GPUs may add up to, which
+    /// may leave fewer than `core_count` of them.
+    max_total_power: Option<u32>,
+    max_temp_delta: Option<u32>,
+    temp_delta_polls: usize,
+    sample_count: usize,
+    sample_spacing: u64,
+    strict_gpu_count: bool,
+    /// Pass the selected GPUs in ascending order instead of the strategy's ranking.
+    sort_output: bool,
+    env_passthrough: Vec<String>,
+    count_stable_polls: usize,
+    reserve_dir: Option<PathBuf>,
+    reserve_for: u64,
+    usage_ledger: Option<PathBuf>,
+    min_free_memory_percent: usize,
+    /// Comparisons of further nvidia-smi query fields each GPU has to pass.
+    filter_fields: Vec<FieldFilter>,
+    /// How the free memory compared with the thresholds is obtained.
+    memory_metric: MemoryMetric,
+    /// How far, in percent, every selected GPU has to exceed the memory and
+    /// utilization thresholds, 0 to accept any that passes them.
+    selection_margin_percent: usize,
+    /// On a terminal, ask before running on a GPU that passes the thresholds by
+    /// less than `confirm_margin_percent`.
+    confirm_when_tight: bool,
+    confirm_margin_percent: usize,
+    nvidia_smi_extra_args: Vec<String>,
+    command_prefix: Option<String>,
+    /// Run the command through `sh -c`, otherwise execute its words as argv.
+    shell: bool,
+    start_banner: Option<String>,
+    stop_banner: Option<String>,
+    finally: Option<String>,
+    /// Run on the selected GPUs before the command, a failure waits again.
+    soak_command: Option<String>,
+    /// Seconds the GPUs of a failed soak run are skipped.
+    soak_cooldown: u64,
+    /// The GPUs of failed soak runs, skipped until the instant.
+    #[serde(skip)]
+    soak_failed: Vec<(Node, usize, Instant)>,
+    secret_env_file: Option<PathBuf>,
+    cgroup: Option<PathBuf>,
+    nodes: Vec<String>,
+    daemon_socket: Option<PathBuf>,
+    drain_file: Option<PathBuf>,
+    /// The wait is paused while this file exists.
+    control_file: Option<PathBuf>,
+    yield_on_drain: bool,
+    yield_on_file: Option<PathBuf>,
+    yield_on_foreign_process: bool,
+    yield_grace: u64,
+    /// Free memory in GiB of a selected GPU under which a warning is logged,
+    /// once selected and while the command runs. It never affects the run.
+    warn_memory_free: Option<f64>,
+    /// Unused share in percent of a selected GPU under which a warning is
+    /// logged, like `warn_memory_free`.
+    warn_gpu_free: Option<usize>,
+    /// Named sets of config fields, overlaid with `--preset`.
+    presets: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+    /// Config fields overlaid by the time of day at launch.
+    schedule: Vec<ScheduleEntry>,
+    /// Read the times of `schedule` in UTC instead of the local time zone.
+    schedule_utc: bool,
+    /// A config file whose fields this one overlays, relative to this one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extends: Option<PathBuf>,
+}
+
+/// Config fields that apply while the time of day is within `start..end`,
+/// both `HH:MM`. A window whose end is before its start wraps past midnight.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ScheduleEntry {
+    start: String,
+    end: String,
+    config: serde_json::Map<String, serde_json::Value>,
+}
+
+/// How to rank the available GPUs when more than `core_count` of them qualify.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum Strategy {
+    /// Prefer the GPUs with the lowest utilization, then the most free memory.
+    #[default]
+    #[value(alias = "util")]
+    LeastUtil,
+    /// Prefer the GPUs with the most free memory, then the lowest utilization.
+    #[value(alias = "memory")]
+    MostFreeMemory,
+    /// Prefer the GPUs that have been available for the longest time across checks.
+    #[value(alias = "newest-idle")]
+    LongestIdle,
+    /// Prefer the GPUs whose last use by this tool is the oldest, from the usage ledger.
+    #[value(alias = "lru")]
+    LeastRecentlyUsed,
+}
+
+/// Where the free memory of a GPU comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum MemoryMetric {
+    /// `memory.free` as reported.
+    #[default]
+    Free,
+    /// `memory.total - memory.used`, for drivers whose `memory.free` is off,
+    /// i.e. under MPS.
+    Computed,
+}
+
+/// How to treat the persistence mode of the GPUs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum PersistenceMode {
+    /// Don't look at it.
+    #[default]
+    Ignore,
+    /// Rank GPUs with persistence mode on first.
+    Prefer,
+    /// Only select GPUs with persistence mode on.
+    Require,
+}
+
+/// The tool querying the GPUs, see [`GpuBackend`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum Backend {
+    /// NVIDIA GPUs with nvidia-smi.
+    #[default]
+    Nvidia,
+    /// AMD GPUs with rocm-smi.
+    Amd,
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about = "The program monitors GPU resource availability\nand executes the specified command if resources are sufficient.", long_about = None)]
 struct Cli {
-    #[arg(short = 'n', long, help = "Specify the GPU core count.")]
-    core_count: Option<usize>,
+    #[command(subcommand)]
+    command: Option<Commands>,
 
-    #[arg(short, long, help = "Set the memory (GB) available on each GPU.")]
-    memory_per_core: Option<u32>,
+    #[command(flatten)]
+    selection: SelectionArgs,
 
-    #[arg(short, long, help = "Set the GPU usage available on each GPU.")]
-    gpu_percent: Option<usize>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Take the free memory as reported (free) or as total minus used (computed)."
+    )]
+    memory_metric: Option<MemoryMetric>,
 
-    #[arg(short = 'k', long, help = "Set the number of checks to perform.")]
-    check_times: Option<usize>,
+    #[arg(
+        long,
+        value_name = "MARGIN_PERCENT",
+        conflicts_with = "best_effort_selected",
+        help = "Only select the GPUs if each exceeds the memory and utilization thresholds by <MARGIN_PERCENT>."
+    )]
+    require_free_for_all_selected: Option<usize>,
 
     #[arg(
-        short = 't',
         long,
-        help = "Specify the interval for checks in seconds."
+        help = "Select the GPUs that pass the thresholds however narrowly, the default."
     )]
-    check_interval: Option<u64>,
+    best_effort_selected: bool,
+
+    #[arg(
+        long,
+        help = "On a terminal, ask before running on a GPU that barely passes the thresholds."
+    )]
+    confirm_when_tight: bool,
+
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        help = "The margin over the thresholds under which --confirm-when-tight asks."
+    )]
+    confirm_margin: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Average <N> quick readings of nvidia-smi within each check."
+    )]
+    sample_count: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "MS",
+        help = "Specify the milliseconds between the readings of --sample-count."
+    )]
+    sample_spacing: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Add a random 0 to <SECONDS> to each interval between checks."
+    )]
+    jitter: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_secs,
+        help = "Double the interval after each check the resource isn't enough, up to <DURATION>."
+    )]
+    backoff_max: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_secs,
+        help = "Give up with exit code 75 after waiting <DURATION> (i.e. 600, 30m), 0 to wait indefinitely."
+    )]
+    max_wait: Option<u64>,
 
     #[arg(short = 'e', long, help = "Set <gpu_env> to the available GPU index.")]
     gpu_env: Option<String>,
 
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Warn if the value of <gpu_env> is longer than <N> characters."
+    )]
+    gpu_env_max_len: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Warn if more than <N> GPUs are set in <gpu_env>."
+    )]
+    gpu_env_max_count: Option<usize>,
+
     #[arg(
         short,
         long,
-        help = "Append environment variables for command execution."
+        help = "Append environment variables for command execution, replacing the config entry of the same variable, \"\" clears the config ones."
     )]
     set_envs: Option<Vec<String>>,
 
+    #[arg(
+        long,
+        help = "Fail if a value of set_envs refers to an undefined variable instead of expanding it to nothing."
+    )]
+    strict_env_expansion: bool,
+
     #[arg(
         short,
         long,
-        help = "Remove specified environment variables for command execution."
+        help = "Remove specified environment variables for command execution, besides the config ones, \"\" clears the config ones."
     )]
     unset_envs: Option<Vec<String>>,
 
+    #[arg(
+        long,
+        value_name = "VERSION",
+        help = "Refuse to run if the NVIDIA driver is older than <VERSION>."
+    )]
+    min_driver_version: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        help = "Only pass the environment variables matching <PATTERN> (i.e. 'NCCL_*') to the command."
+    )]
+    env_passthrough: Vec<String>,
+
     #[arg(
         short,
         long,
         value_name = "FILE",
+        global = true,
         help = "Read configuration from the specified file path."
     )]
     config_path: Option<PathBuf>,
 
-    #[arg(short, long, help = "Print the current configuration.")]
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        default_value = "WIZARD_",
+        help = "Read config overrides from environment variables named <PREFIX><FIELD>, i.e. WIZARD_CORE_COUNT."
+    )]
+    config_env_prefix: String,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        visible_alias = "select-by-template",
+        global = true,
+        help = "Overlay the fields of the preset <NAME> from the config's presets."
+    )]
+    preset: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        help = "Print the current configuration (deprecated, use `config --print`)."
+    )]
     print_config: bool,
 
-    #[arg(short = 'w', long, help = "Save the current configuration to a file.")]
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ConfigFormat::Json,
+        global = true,
+        help = "Print the configuration of --print-config as JSON, TOML or YAML."
+    )]
+    format: ConfigFormat,
+
+    #[arg(
+        short = 'w',
+        long,
+        help = "Save the current configuration to a file (deprecated, use `config --save`)."
+    )]
     save_config: bool,
 
-    #[arg(help = "Specify the command to execute.")]
-    cmd: Vec<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Create the missing parent directories of the config file when saving it."
+    )]
+    create_dirs: bool,
 
-    #[arg(short, long, help = "Increase output verbosity.")]
-    verbose: bool,
-}
+    #[arg(
+        long,
+        value_enum,
+        help = "Prefer or require GPUs with persistence mode on, avoiding the driver init latency."
+    )]
+    persistence_mode: Option<PersistenceMode>,
 
-fn main() {
-    let cli = Cli::parse();
-    if cli.verbose {
-        Builder::new().filter_level(LevelFilter::Info).init();
-    } else {
-        match env::var("RUST_LOG") {
-            Ok(_) => {
-                Builder::from_default_env().init();
-            }
-            Err(_) => {
-                Builder::new().filter_level(LevelFilter::Warn).init();
-            }
-        }
-    }
+    #[arg(
+        long,
+        value_enum,
+        help = "Query the GPUs with nvidia-smi (nvidia) or rocm-smi (amd)."
+    )]
+    backend: Option<Backend>,
 
-    let (config_path, strict) = match cli.config_path.as_deref() {
-        Some(v) => (v, true),
-        None => (Path::new(".plan.json"), false),
-    };
-    let mut config = read_config_from_file(config_path, strict);
+    #[arg(
+        long,
+        value_name = "STRATEGY",
+        help = "Explain how <STRATEGY> filters and ranks the GPUs with the current config, then exit."
+    )]
+    explain_strategy: Option<Strategy>,
 
-    if let Some(v) = cli.core_count {
-        config.core_count = v;
-    }
-    if let Some(v) = cli.memory_per_core {
-        config.memory_per_core = v;
-    }
-    if let Some(v) = cli.gpu_percent {
-        config.gpu_percent = v;
-    }
-    if let Some(v) = cli.check_times {
-        config.check_times = v;
-    }
-    if let Some(v) = cli.check_interval {
-        config.check_interval = v;
-    }
-    if let Some(v) = cli.gpu_env {
-        config.gpu_env = v;
-    }
-    if let Some(v) = cli.set_envs {
-        if v.len() > 0 {
-            config.set_envs = v;
-        }
-    }
-    if let Some(v) = cli.unset_envs {
-        if v.len() > 0 {
-            config.unset_envs = v;
-        }
-    }
+    #[arg(
+        long,
+        help = "List the strategies with their aliases and what they prefer, then exit."
+    )]
+    list_strategies: bool,
 
-    if cli.print_config {
-        println!(
-            "Current config:\n{}",
-            serde_json::to_string_pretty(&config).unwrap()
-        );
-        return;
-    } else {
-        info!(
-            "Current config:\n{}",
-            serde_json::to_string_pretty(&config).unwrap()
-        );
-    }
+    #[arg(
+        long,
+        conflicts_with = "strategy",
+        help = "Prefer the GPUs that have been idle the longest (--strategy longest-idle)."
+    )]
+    select_newest_idle: bool,
 
-    if cli.save_config {
-        save_config(&config, config_path);
-    }
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        help = "Report a near miss when the closest GPU is within <PERCENT> of the thresholds."
+    )]
+    near_miss_percent: Option<usize>,
 
-    if cli.cmd.len() == 0 {
-        Cli::command().print_help().unwrap();
-        return;
-    }
+    #[arg(
+        long,
+        value_name = "CMD",
+        help = "Run <CMD> when the closest GPU becomes a near miss while waiting."
+    )]
+    notify_command: Option<String>,
 
-    let gpus = wait_for_resource(
-        config.core_count,
-        config.memory_per_core,
-        config.gpu_percent,
-        config.check_times,
-        config.check_interval,
-    );
+    #[arg(
+        long,
+        help = "Print a best-effort estimate of when enough GPUs will be available while waiting."
+    )]
+    show_eta: bool,
 
-    run_command(
-        &cli.cmd.join(" "),
-        &gpus.join(","),
-        &config.gpu_env,
-        &config.set_envs,
-        &config.unset_envs,
-    );
-}
+    #[arg(
+        long,
+        help = "Check again right away when receiving SIGUSR1 while waiting."
+    )]
+    poll_immediately_on_signal: bool,
 
-fn parse_cuda_info() -> Vec<GPUInfo> {
-    let mut gpu_info_list = vec![];
-    let output = Command::new("nvidia-smi")
-        .args([
-            "--query-gpu",
-            "index,utilization.gpu,memory.free",
-            "--format",
-            "csv,noheader",
-        ])
-        .output()
-        .expect("nvidia-smi execute failed");
-    for line in String::from_utf8(output.stdout).unwrap().split("\n") {
-        if line.len() == 0 {
-            break;
-        }
-        let mut field_it = line.split(", ");
-        let index = field_it.next().unwrap().parse::<usize>().unwrap();
-        let gpu_percent = field_it.next().unwrap();
-        let gpu_percent = gpu_percent[..gpu_percent.len() - 2]
-            .parse::<usize>()
-            .unwrap();
-        let memory_free = field_it.next().unwrap();
-        let memory_free = memory_free[..memory_free.len() - 4].parse::<u32>().unwrap();
-        let gpu_info = GPUInfo {
-            index,
-            gpu_free: 100 - gpu_percent,
-            memory_free: memory_free / 1024,
-        };
-        gpu_info_list.push(gpu_info);
-    }
-    info!("{:?}", gpu_info_list);
-    gpu_info_list
-}
+    #[arg(
+        long,
+        value_name = "STR",
+        help = "Prepend <STR> to the command, i.e. 'nsys profile -o report'."
+    )]
+    command_prefix: Option<String>,
 
-fn check_resource_enough(
-    gpu_info_list: &Vec<GPUInfo>,
-    core_count: usize,
-    memory_per_core: u32,
-    gpu_percent: usize,
-) -> Option<Vec<String>> {
-    let mut available_gpu = vec![];
-    for gpu_info in gpu_info_list {
-        if gpu_info.memory_free >= memory_per_core && gpu_info.gpu_free >= gpu_percent {
-            available_gpu.push((gpu_info.index, gpu_info.gpu_free));
-        }
-    }
-    if available_gpu.len() >= core_count {
-        available_gpu.sort_by_key(|x| 100 - x.1);
-        let gpus = available_gpu
-            .iter()
-            .map(|x| x.0.to_string())
-            .collect::<Vec<String>>();
+    #[arg(
+        long,
+        overrides_with = "no_shell",
+        help = "Run the command through `sh -c`, the default."
+    )]
+    shell: bool,
 
-        return Some(gpus[0..core_count].to_vec());
-    }
-    return None;
-}
+    #[arg(
+        long,
+        overrides_with = "shell",
+        help = "Execute the first word of the command with the others as literal arguments, without a shell."
+    )]
+    no_shell: bool,
 
-fn wait_for_resource(
-    core_count: usize,
-    memory_per_core: u32,
-    gpu_percent: usize,
-    cum_count: usize,
-    interval_sec: u64,
-) -> Vec<String> {
-    let mut cur_count = 0;
-    loop {
-        let gpu_info_list = parse_cuda_info();
-        match check_resource_enough(&gpu_info_list, core_count, memory_per_core, gpu_percent) {
-            Some(gpus) => {
-                cur_count += 1;
-                info!("Resource is enough: {}", cur_count);
-                if cur_count >= cum_count {
-                    return gpus;
-                }
-            }
-            None => {
-                cur_count = 0;
-                info!("Resource isn't enough.");
-            }
-        }
-        sleep(Duration::from_secs(interval_sec));
-    }
-}
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        help = "Print <TEMPLATE> when the command starts, with {cmd}, {gpus}, {gpu_count} and {timestamp} replaced, empty for none."
+    )]
+    start_banner: Option<String>,
 
-fn run_command(
-    cmd: &str,
-    gpus: &str,
-    gpu_env: &String,
-    env: &Vec<String>,
-    env_clear: &Vec<String>,
-) {
-    println!(r"*** Start run `{}` ***", &cmd);
-    println!(r"*** Using GPU `{}` ***", &gpus);
-    let mut command = Command::new("sh");
-    command.arg("-c").arg(&cmd).env(gpu_env, gpus);
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        help = "Print <TEMPLATE> when the command stops, like --start-banner."
+    )]
+    stop_banner: Option<String>,
 
-    for s in env {
-        let kvs: Vec<&str> = s.splitn(2, "=").collect();
-        if kvs.len() != 2 {
-            warn!("set_envs parse error: {}", s);
-            continue;
-        }
-        command.env(kvs[0], kvs[1]);
-    }
-    for s in env_clear {
-        command.env_remove(s);
-    }
+    #[arg(
+        long,
+        value_name = "CMD",
+        help = "Run <CMD> once on any exit, with WIZARD_EXIT_REASON telling why."
+    )]
+    finally: Option<String>,
 
-    let mut child = command.spawn().expect("Execute cmd failed");
-    let status = child.wait().unwrap();
-    println!(r"*** Stop run ***");
-    exit(status.code().unwrap());
-}
+    #[arg(
+        long,
+        value_name = "CMD",
+        help = "Run <CMD> on the selected GPUs first, on failure wait again without them."
+    )]
+    soak_command: Option<String>,
 
-fn default_config() -> Config {
-    Config {
-        core_count: 1,
-        memory_per_core: 1,
-        gpu_percent: 50,
-        check_times: 1,
-        check_interval: 15,
-        gpu_env: "CUDA_VISIBLE_DEVICES".to_string(),
-        set_envs: vec![],
-        unset_envs: vec![],
-    }
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_secs,
+        help = "Skip the GPUs of a failed soak command for <DURATION> (i.e. 600, 30m)."
+    )]
+    soak_cooldown: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Set the KEY=VALUE lines of <PATH> in the command's environment, never logging the values."
+    )]
+    secret_env_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Run the command in the existing cgroup <PATH> (Linux only)."
+    )]
+    cgroup: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "HOSTS",
+        value_delimiter = ',',
+        help = "Wait on the comma separated <HOSTS> over ssh and run on the first one ready."
+    )]
+    nodes: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "SOCKET",
+        conflicts_with_all = ["cmd", "emit_exports", "emit_var", "print_bitmask", "exit_bitmask", "no_exec"],
+        help = "Serve selections to --daemon-socket clients on the Unix socket <SOCKET>, polling the GPUs every check_interval."
+    )]
+    daemon: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "SOCKET",
+        help = "Ask the daemon on <SOCKET> (see --daemon) for the GPUs instead of querying nvidia-smi."
+    )]
+    daemon_socket: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Allow GPUs that already run a process of the current user, the default."
+    )]
+    allow_self_stacking: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "allow_self_stacking",
+        help = "Skip GPUs that already run a process of the current user, querying the processes on every check."
+    )]
+    avoid_self_stacking: bool,
+
+    #[arg(
+        long,
+        help = "Only select GPUs without any compute process, whatever their utilization and free memory."
+    )]
+    require_exclusive: bool,
+
+    #[arg(long, help = "Only select GPUs behind distinct PCIe root complexes.")]
+    spread_pcie: bool,
+
+    #[arg(long, help = "Skip GPUs reporting uncorrected ECC errors.")]
+    skip_ecc_errors: bool,
+
+    #[arg(
+        long,
+        value_name = "WATTS",
+        conflicts_with = "tasks",
+        help = "Keep the power limits of the selected GPUs under <WATTS> in total, selecting fewer than core_count if needed."
+    )]
+    max_total_power: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "CELSIUS",
+        help = "Skip GPUs whose temperature rose more than <CELSIUS> over the last --temp-delta-polls checks."
+    )]
+    max_temp_delta: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Specify over how many checks --max-temp-delta measures the rise."
+    )]
+    temp_delta_polls: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Exit with an error instead of a warning if the command is too long to spawn."
+    )]
+    strict_arg_max: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Record utilization and memory of the selected GPUs to a csv file during the run."
+    )]
+    profile_output: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 1.0,
+        help = "Specify the sampling interval for --profile-output."
+    )]
+    profile_interval: f64,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Don't start the command while <PATH> exists, the node is being drained."
+    )]
+    drain_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Pause the wait while <PATH> exists, without selecting any GPU, and resume once it is removed."
+    )]
+    control_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Also terminate a running command when the drain file appears."
+    )]
+    yield_on_drain: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Terminate the command when <PATH> appears, to give the GPUs back."
+    )]
+    yield_on_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        alias = "abort-if-newer-job-arrives",
+        help = "Terminate the command when a process of another user starts on its GPUs."
+    )]
+    yield_on_foreign_process: bool,
+
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_secs,
+        help = "Specify how long a yielding command may take to exit before it is killed."
+    )]
+    yield_grace: Option<u64>,
+
+    #[arg(
+        long = "warn-threshold",
+        value_name = "KIND=VALUE",
+        value_parser = parse_warn_threshold,
+        help = "Warn when a selected GPU drops under memory=<GIB> free or gpu=<PERCENT> unused, without affecting the run, can be repeated."
+    )]
+    warn_thresholds: Vec<WarnThreshold>,
+
+    #[arg(
+        long,
+        help = "Exit with an error if there are fewer GPUs than <CORE_COUNT>."
+    )]
+    strict_gpu_count: bool,
+
+    #[arg(
+        long,
+        help = "Set and print the selected GPUs in ascending order instead of best first."
+    )]
+    sort_output: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Only trust the GPU count once it is non-zero and unchanged for <N> checks."
+    )]
+    count_stable_polls: Option<usize>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHEN",
+        num_args = 0..=1,
+        default_missing_value = "final",
+        help = "Print the evaluation of every GPU as JSON for the final or every check."
+    )]
+    print_selection_json: Option<SelectionJson>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Reserve the selected GPUs with lock files in <PATH> shared by all instances."
+    )]
+    reserve_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_secs,
+        help = "Keep the lock files for <DURATION> (i.e. 90s, 5m) after the command exits."
+    )]
+    reserve_for: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Record when the commands use each GPU in <PATH>, read by --strategy least-recently-used."
+    )]
+    usage_ledger: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Reuse the last selected GPUs if they still satisfy the thresholds."
+    )]
+    reuse_last: bool,
+
+    #[cfg(feature = "nvml")]
+    #[arg(
+        long,
+        help = "Read the local GPUs through the NVML library instead of running nvidia-smi each check."
+    )]
+    nvml: bool,
+
+    #[cfg(feature = "notify")]
+    #[arg(
+        long,
+        help = "Show a desktop notification with the exit code, GPUs and duration once the command exits."
+    )]
+    notify_on_complete: bool,
+
+    #[cfg(feature = "otel")]
+    #[arg(
+        long,
+        value_name = "URL",
+        help = "Export OpenTelemetry spans to the OTLP/HTTP endpoint <URL>."
+    )]
+    otel_endpoint: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "cmd",
+        help = "Print the selection as shell `export` lines instead of running a command."
+    )]
+    emit_exports: bool,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        value_parser = parse_env_name,
+        conflicts_with_all = ["cmd", "emit_exports"],
+        help = "Print the selection as the single line <NAME>=<GPUS> instead of running a command, i.e. for $(...) or $GITHUB_ENV."
+    )]
+    emit_var: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["cmd", "emit_exports", "emit_var"],
+        help = "Print the selection as a decimal bitmask (bit i for GPU i, GPUs 0-127) instead of running a command."
+    )]
+    print_bitmask: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["cmd", "emit_exports", "emit_var", "print_bitmask"],
+        help = "Exit with the selection as a bitmask (bit i for GPU i, GPUs 0-7 only) instead of running a command."
+    )]
+    exit_bitmask: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["cmd", "emit_exports", "emit_var", "print_bitmask", "exit_bitmask"],
+        help = "Print the selection and exit instead of running a command."
+    )]
+    no_exec: bool,
+
+    #[arg(
+        long,
+        help = "Check the GPUs once, print the GPUs, command and environment that would be used, then exit (as a JSON verdict with --output json)."
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        conflicts_with_all = ["emit_exports", "emit_var", "print_bitmask", "exit_bitmask"],
+        help = "Print the selection to stdout in <OUTPUT> once the GPUs are selected, with the banners on stderr."
+    )]
+    output: OutputFormat,
+
+    #[arg(
+        long,
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        help = "Append <ARG> to the nvidia-smi GPU query (i.e. '--id=0,1'), can be repeated."
+    )]
+    nvidia_smi_extra_args: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Print the nvidia-smi GPU query and its raw output, then exit."
+    )]
+    dump_nvidia_smi: bool,
+
+    #[arg(
+        long = "task",
+        value_name = "CMD",
+        conflicts_with_all = [
+            "cmd",
+            "emit_exports",
+            "emit_var",
+            "print_bitmask",
+            "exit_bitmask",
+            "no_exec",
+            "dry_run",
+            "wait_for_indices",
+            "best_effort_selected",
+            "profile_output",
+        ],
+        help = "Run the shell command <CMD> on its own core_count GPUs, concurrently with the other tasks, can be repeated."
+    )]
+    tasks: Vec<String>,
+
+    #[arg(
+        trailing_var_arg = true,
+        help = "Specify the command to execute, everything after it (or after `--`) is passed verbatim (deprecated, use `run`)."
+    )]
+    cmd: Vec<String>,
+
+    #[arg(short, long, global = true, help = "Increase output verbosity.")]
+    verbose: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LogFormat::Text,
+        help = "Set the format of log lines."
+    )]
+    log_format: LogFormat,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHERE",
+        num_args = 0..=1,
+        default_missing_value = "also",
+        help = "Send the log to the system logger as well as stderr, or only to it."
+    )]
+    syslog: Option<SyslogMode>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Append the log to the file <PATH> as well, at the same level as on stderr."
+    )]
+    log_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Write the lifecycle events (waiting, acquired, launched, exited) to stderr as one JSON object per line."
+    )]
+    events_ndjson: bool,
+
+    #[arg(
+        long,
+        help = "Print help with examples, exit codes and environment variables."
+    )]
+    help_verbose: bool,
+}
+
+/// The options deciding which GPUs qualify and are selected, shared by the
+/// subcommands, so they go before or after them.
+#[derive(Args, Debug)]
+struct SelectionArgs {
+    #[arg(short = 'n', long, global = true, help = "Specify the GPU core count.")]
+    core_count: Option<usize>,
+
+    #[arg(
+        long = "wait-for-index",
+        value_name = "N",
+        conflicts_with = "core_count",
+        global = true,
+        help = "Wait until the GPU with index <N> is available and select it, can be repeated."
+    )]
+    wait_for_indices: Vec<usize>,
+
+    #[arg(
+        long,
+        value_name = "INDICES",
+        value_delimiter = ',',
+        global = true,
+        help = "Only select among the comma separated GPU <INDICES>."
+    )]
+    allow_gpus: Vec<usize>,
+
+    #[arg(
+        long,
+        value_name = "INDICES",
+        value_delimiter = ',',
+        global = true,
+        help = "Never select the comma separated GPU <INDICES>."
+    )]
+    deny_gpus: Vec<usize>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        global = true,
+        help = "Leave the GPUs listed in <FILE> to the pending larger job that declared them."
+    )]
+    respect_pending_reservation: Option<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "Set the memory (GB) available on each GPU."
+    )]
+    memory_per_core: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        conflicts_with = "memory_per_core",
+        global = true,
+        help = "Set the memory available on each GPU as <PERCENT> of its total memory, instead of --memory-per-core."
+    )]
+    memory_percent: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "GIB",
+        global = true,
+        help = "Subtract <GIB> of driver overhead from the total memory of each GPU before taking percentages of it."
+    )]
+    memory_overhead: Option<f64>,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "Set the GPU usage available on each GPU."
+    )]
+    gpu_percent: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        global = true,
+        help = "Require at least <PERCENT> of the total memory to be free on each GPU."
+    )]
+    min_free_memory_percent: Option<usize>,
+
+    #[arg(
+        long = "filter-field",
+        value_name = "FIELD OP VALUE",
+        value_parser = FieldFilter::from_str,
+        global = true,
+        help = "Require the nvidia-smi query field <FIELD> of each GPU to compare with <VALUE> by <, <=, >, >= or ==, i.e. 'fan.speed < 80', can be repeated."
+    )]
+    filter_fields: Vec<FieldFilter>,
+
+    #[arg(
+        short = 'k',
+        long,
+        global = true,
+        help = "Set the number of checks to perform."
+    )]
+    check_times: Option<usize>,
+
+    #[arg(
+        short = 't',
+        long,
+        global = true,
+        help = "Specify the interval for checks in seconds."
+    )]
+    check_interval: Option<u64>,
+
+    #[arg(
+        long,
+        alias = "select-by",
+        global = true,
+        help = "Set the strategy to rank the available GPUs."
+    )]
+    strategy: Option<Strategy>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Wait for the GPUs and run the command on them.
+    Run {
+        #[arg(
+            required = true,
+            trailing_var_arg = true,
+            help = "The command to execute, everything after it (or after `--`) is passed verbatim."
+        )]
+        cmd: Vec<String>,
+    },
+    /// Check the GPUs once and print them with whether each is available.
+    Status,
+    /// Print or save the configuration with all overrides applied.
+    Config {
+        #[arg(long, help = "Print the configuration, the default without --save.")]
+        print: bool,
+        #[arg(long, help = "Save the configuration to the config file.")]
+        save: bool,
+    },
+    /// Inspect or clean the lock files in the reserve dir.
+    Locks {
+        #[command(subcommand)]
+        action: LocksAction,
+    },
+    /// Check that nvidia-smi works and report the driver, the CUDA version and
+    /// the GPU count, exit with 1 if something is wrong.
+    Doctor {
+        #[arg(long, help = "Print the result as one JSON object.")]
+        json_output: bool,
+    },
+    /// Measure the time (and with the bench feature the allocations) of parsing
+    /// the nvidia-smi output of a synthetic node, with a fresh GPU list and with
+    /// one reused across checks. With `--query`, measure the checks of the local
+    /// GPUs instead.
+    Bench {
+        #[arg(long, default_value_t = 16, help = "The GPU count of the node.")]
+        gpus: usize,
+        #[arg(long, default_value_t = 10000, help = "The parses to measure.")]
+        iterations: u32,
+        #[arg(
+            long,
+            help = "Time real checks through nvidia-smi and, if enabled, NVML (use a small --iterations)."
+        )]
+        query: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum LocksAction {
+    /// List all locks with their owner, age and whether the owner is alive.
+    List,
+    /// Remove the locks whose owner is dead.
+    Clean {
+        #[arg(long, help = "Also remove the locks kept by --reserve-for.")]
+        force: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SelectionJson {
+    /// Only the check that selected the GPUs.
+    Final,
+    /// Every check while waiting.
+    EveryCheck,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Only the banners, or the GPU list with --no-exec.
+    Text,
+    /// One JSON object with the selection.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// `key=value` pairs per line.
+    Logfmt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SyslogMode {
+    /// Log to the system logger and stderr.
+    Also,
+    /// Log to the system logger only.
+    Only,
+}
+
+/// Sends the records enabled by `logger` to the system logger, and passes
+/// them on to `logger` unless `only`.
+struct SyslogLogger {
+    logger: env_logger::Logger,
+    syslog: Mutex<syslog::Logger<syslog::LoggerBackend, syslog::Formatter3164>>,
+    only: bool,
+}
+
+impl SyslogLogger {
+    fn connect() -> syslog::Result<syslog::Logger<syslog::LoggerBackend, syslog::Formatter3164>> {
+        syslog::unix(syslog::Formatter3164 {
+            facility: syslog::Facility::LOG_USER,
+            hostname: None,
+            process: env!("CARGO_PKG_NAME").into(),
+            pid: std::process::id(),
+        })
+    }
+}
+
+impl log::Log for SyslogLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.logger.matches(record) {
+            return;
+        }
+        let mut msg = record.args().to_string();
+        for (k, v) in log_fields(record) {
+            let v = match v {
+                serde_json::Value::String(v) => logfmt_value(&v),
+                v => v.to_string(),
+            };
+            msg.push_str(&format!(" {}={}", k, v));
+        }
+        let mut syslog = self.syslog.lock().unwrap_or_else(|e| e.into_inner());
+        // the message is lost if the logger is gone, there is nowhere to report it
+        let _ = match record.level() {
+            log::Level::Error => syslog.err(msg),
+            log::Level::Warn => syslog.warning(msg),
+            log::Level::Info => syslog.info(msg),
+            log::Level::Debug | log::Level::Trace => syslog.debug(msg),
+        };
+        drop(syslog);
+        if !self.only {
+            self.logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+    }
+}
+
+/// Passes the records to `logger` and also writes them to `file`, a logger of
+/// the same level and format.
+struct TeeLogger {
+    logger: Box<dyn log::Log>,
+    file: env_logger::Logger,
+}
+
+impl log::Log for TeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.logger.enabled(metadata) || self.file.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.logger.log(record);
+        self.file.log(record);
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+        self.file.flush();
+    }
+}
+
+fn init_logger(
+    verbose: bool,
+    format: LogFormat,
+    syslog: Option<SyslogMode>,
+    log_file: Option<&Path>,
+) {
+    let logger = logger_builder(verbose, format).build();
+    log::set_max_level(logger.filter());
+    let mut warnings = vec![];
+    let logger: Box<dyn log::Log> = match syslog {
+        None => Box::new(logger),
+        Some(mode) => match SyslogLogger::connect() {
+            Ok(syslog) => Box::new(SyslogLogger {
+                logger,
+                syslog: Mutex::new(syslog),
+                only: mode == SyslogMode::Only,
+            }),
+            Err(e) => {
+                // keep logging to stderr, and say why once the logger is set
+                warnings.push(format!(
+                    "Connect to the system logger failed, log to stderr: {}",
+                    e
+                ));
+                Box::new(logger)
+            }
+        },
+    };
+    let file = log_file.and_then(|path| {
+        match fs::OpenOptions::new().create(true).append(true).open(path) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warnings.push(format!("Open log file {} failed: {}", path.display(), e));
+                None
+            }
+        }
+    });
+    let _ = match file {
+        Some(file) => {
+            let file = logger_builder(verbose, format)
+                .target(env_logger::Target::Pipe(Box::new(file)))
+                .write_style(env_logger::WriteStyle::Never)
+                .build();
+            log::set_boxed_logger(Box::new(TeeLogger { logger, file }))
+        }
+        None => log::set_boxed_logger(logger),
+    };
+    for x in warnings {
+        warn!("{}", x);
+    }
+}
+
+/// The level from `--verbose` or `RUST_LOG`, and the format of the lines.
+fn logger_builder(verbose: bool, format: LogFormat) -> Builder {
+    let mut builder = if verbose {
+        let mut builder = Builder::new();
+        builder.filter_level(LevelFilter::Info);
+        builder
+    } else {
+        match env::var("RUST_LOG") {
+            Ok(_) => Builder::from_default_env(),
+            Err(_) => {
+                let mut builder = Builder::new();
+                builder.filter_level(LevelFilter::Warn);
+                builder
+            }
+        }
+    };
+    match format {
+        LogFormat::Text => {}
+        LogFormat::Json => {
+            builder.format(|buf, record| {
+                let mut fields = serde_json::Map::new();
+                fields.insert("ts".into(), buf.timestamp().to_string().into());
+                fields.insert("level".into(), record.level().as_str().into());
+                fields.insert("target".into(), record.target().into());
+                fields.insert("msg".into(), record.args().to_string().into());
+                for (k, v) in log_fields(record) {
+                    fields.insert(k, v);
+                }
+                writeln!(buf, "{}", serde_json::Value::Object(fields))
+            });
+        }
+        LogFormat::Logfmt => {
+            builder.format(|buf, record| {
+                let mut line = format!(
+                    "ts={} level={} target={} msg={}",
+                    buf.timestamp(),
+                    record.level().as_str().to_lowercase(),
+                    logfmt_value(record.target()),
+                    logfmt_value(&record.args().to_string())
+                );
+                for (k, v) in log_fields(record) {
+                    let v = match v {
+                        serde_json::Value::String(v) => logfmt_value(&v),
+                        v => v.to_string(),
+                    };
+                    line.push_str(&format!(" {}={}", k, v));
+                }
+                writeln!(buf, "{}", line)
+            });
+        }
+    }
+    builder
+}
+
+/// The structured fields attached to a log record, i.e. `info!(poll = 3; "...")`.
+fn log_fields(record: &log::Record) -> Vec<(String, serde_json::Value)> {
+    struct Collect(Vec<(String, serde_json::Value)>);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Collect {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> Result<(), log::kv::Error> {
+            let value = if let Some(v) = value.to_u64() {
+                v.into()
+            } else if let Some(v) = value.to_i64() {
+                v.into()
+            } else if let Some(v) = value.to_f64() {
+                v.into()
+            } else if let Some(v) = value.to_bool() {
+                v.into()
+            } else {
+                value.to_string().into()
+            };
+            self.0.push((key.to_string(), value));
+            Ok(())
+        }
+    }
+
+    let mut fields = Collect(vec![]);
+    let _ = record.key_values().visit(&mut fields);
+    fields.0
+}
+
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty()
+        && !value.contains(|c: char| c == ' ' || c == '=' || c == '"' || c.is_control())
+    {
+        value.to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+fn main() {
+    let mut cli = Cli::parse();
+    init_logger(
+        cli.verbose,
+        cli.log_format,
+        cli.syslog,
+        cli.log_file.as_deref(),
+    );
+    if cli.help_verbose {
+        print!("{}", verbose_help());
+        return;
+    }
+    EVENTS_NDJSON.store(cli.events_ndjson, Ordering::Relaxed);
+    if cli.list_strategies {
+        print!("{}", list_strategies());
+        return;
+    }
+    // `run` takes the place of the command given without a subcommand, which
+    // still works until it is removed
+    if let Some(Commands::Run { cmd }) = &mut cli.command {
+        let conflict = [
+            ("--daemon", cli.daemon.is_some()),
+            ("--emit-exports", cli.emit_exports),
+            ("--emit-var", cli.emit_var.is_some()),
+            ("--print-bitmask", cli.print_bitmask),
+            ("--exit-bitmask", cli.exit_bitmask),
+            ("--no-exec", cli.no_exec),
+            ("--task", !cli.tasks.is_empty()),
+        ]
+        .into_iter()
+        .find(|x| x.1);
+        if let Some((arg, _)) = conflict {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    format!("the argument '{}' cannot be used with 'run'", arg),
+                )
+                .exit();
+        }
+        cli.cmd = std::mem::take(cmd);
+        cli.command = None;
+    } else if !cli.cmd.is_empty() {
+        warn!("Giving the command without `run` is deprecated, use `run <CMD>...`");
+    }
+    if has_blank_command(&cli.cmd, &cli.tasks) {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::InvalidValue,
+                "the command is empty or only whitespace",
+            )
+            .exit();
+    }
+    if cli.print_config || cli.save_config {
+        warn!(
+            "--print-config and --save-config are deprecated, use `config --print` or `config --save`"
+        );
+    }
+
+    let (config_path, strict) = match cli.config_path.as_deref() {
+        Some(v) if v.is_dir() => (discover_config(v), true),
+        Some(v) => (v.to_path_buf(), true),
+        None => (PathBuf::from(".plan.json"), false),
+    };
+    (cli.print_config, cli.save_config) = config_operations(&cli);
+    let executes = has_execution(&cli);
+    // saving may create the config file
+    let strict = strict && !cli.save_config;
+    let config_path = config_path.as_path();
+    let mut config = read_config_from_file(config_path, strict);
+    config = apply_env_overrides(config, &cli.config_env_prefix);
+    config = apply_schedule(config);
+    if let Some(v) = &cli.preset {
+        config = apply_preset(config, v);
+    }
+
+    if let Some(v) = cli.selection.core_count {
+        config.core_count = v;
+    }
+    if !cli.selection.wait_for_indices.is_empty() {
+        config.wait_for_indices = cli.selection.wait_for_indices;
+    }
+    if !cli.selection.allow_gpus.is_empty() {
+        config.allow_gpus = cli.selection.allow_gpus;
+    }
+    if !cli.selection.deny_gpus.is_empty() {
+        config.deny_gpus = cli.selection.deny_gpus;
+    }
+    if let Some(v) = cli.selection.respect_pending_reservation {
+        config.pending_reservation = Some(v);
+    }
+    if let Some(v) = cli.selection.memory_per_core {
+        config.memory_per_core = v;
+        config.memory_percent = None;
+    }
+    if let Some(v) = cli.selection.memory_percent {
+        config.memory_percent = Some(v);
+    }
+    if let Some(v) = cli.selection.memory_overhead {
+        config.memory_overhead = v;
+    }
+    let memory_by_count =
+        cli.selection.memory_per_core.is_none() && config.memory_percent.is_none();
+    if let Some(v) = cli.selection.gpu_percent {
+        config.gpu_percent = v;
+    }
+    if let Some(v) = cli.selection.min_free_memory_percent {
+        config.min_free_memory_percent = v;
+    }
+    config.filter_fields.extend(cli.selection.filter_fields);
+    if let Some(v) = cli.memory_metric {
+        config.memory_metric = v;
+    }
+    if let Some(v) = cli.require_free_for_all_selected {
+        config.selection_margin_percent = v;
+    }
+    if cli.confirm_when_tight {
+        config.confirm_when_tight = true;
+    }
+    if let Some(v) = cli.confirm_margin {
+        config.confirm_margin_percent = v;
+    }
+    if cli.best_effort_selected {
+        config.selection_margin_percent = 0;
+    }
+    if let Some(v) = cli.sample_count {
+        config.sample_count = v;
+    }
+    if let Some(v) = cli.sample_spacing {
+        config.sample_spacing = v;
+    }
+    if let Some(v) = cli.selection.check_times {
+        config.check_times = v;
+    }
+    if let Some(v) = cli.selection.check_interval {
+        config.check_interval = v;
+    }
+    if let Some(v) = cli.jitter {
+        config.jitter = v;
+    }
+    if let Some(v) = cli.backoff_max {
+        config.backoff_max = v;
+    }
+    if let Some(v) = cli.max_wait {
+        config.max_wait = v;
+    }
+    if let Some(v) = cli.gpu_env {
+        config.gpu_env = v;
+    }
+    if let Some(v) = cli.gpu_env_max_len {
+        config.gpu_env_max_len = Some(v);
+    }
+    if let Some(v) = cli.gpu_env_max_count {
+        config.gpu_env_max_count = Some(v);
+    }
+    if let Some(v) = cli.min_driver_version {
+        config.min_driver_version = Some(v);
+    }
+    if let Some(v) = cli.selection.strategy {
+        config.strategy = v;
+    }
+    if let Some(v) = cli.persistence_mode {
+        config.persistence_mode = v;
+    }
+    if let Some(v) = cli.backend {
+        config.backend = v;
+    }
+    #[cfg(feature = "nvml")]
+    if cli.nvml {
+        config.nvml = true;
+    }
+    if cli.select_newest_idle {
+        config.strategy = Strategy::LongestIdle;
+    }
+    if let Some(v) = cli.near_miss_percent {
+        config.near_miss_percent = v;
+    }
+    if let Some(v) = cli.notify_command {
+        config.notify_command = Some(v);
+    }
+    if cli.show_eta {
+        config.show_eta = true;
+    }
+    if let Some(v) = cli.command_prefix {
+        config.command_prefix = Some(v);
+    }
+    if cli.shell {
+        config.shell = true;
+    }
+    if cli.no_shell {
+        config.shell = false;
+    }
+    if let Some(v) = cli.start_banner {
+        config.start_banner = Some(v);
+    }
+    if let Some(v) = cli.stop_banner {
+        config.stop_banner = Some(v);
+    }
+    if let Some(v) = cli.finally {
+        config.finally = Some(v);
+    }
+    if let Some(v) = cli.soak_command {
+        config.soak_command = Some(v);
+    }
+    if let Some(v) = cli.soak_cooldown {
+        config.soak_cooldown = v;
+    }
+    if let Some(v) = cli.secret_env_file {
+        config.secret_env_file = Some(v);
+    }
+    if let Some(v) = cli.cgroup {
+        config.cgroup = Some(v);
+    }
+    if !cli.nodes.is_empty() {
+        config.nodes = cli.nodes;
+    }
+    if let Some(v) = cli.daemon_socket {
+        config.daemon_socket = Some(v);
+    }
+    if cli.allow_self_stacking {
+        config.allow_self_stacking = true;
+    }
+    if cli.avoid_self_stacking {
+        config.allow_self_stacking = false;
+    }
+    if cli.require_exclusive {
+        config.require_exclusive = true;
+    }
+    if cli.spread_pcie {
+        config.spread_pcie = true;
+    }
+    if cli.skip_ecc_errors {
+        config.skip_ecc_errors = true;
+    }
+    if let Some(v) = cli.max_total_power {
+        config.max_total_power = Some(v);
+    }
+    if let Some(v) = cli.max_temp_delta {
+        config.max_temp_delta = Some(v);
+    }
+    if let Some(v) = cli.temp_delta_polls {
+        config.temp_delta_polls = v;
+    }
+    if cli.strict_gpu_count {
+        config.strict_gpu_count = true;
+    }
+    if cli.sort_output {
+        config.sort_output = true;
+    }
+    config.env_passthrough.extend(cli.env_passthrough);
+    if let Some(v) = cli.count_stable_polls {
+        config.count_stable_polls = v;
+    }
+    if let Some(v) = cli.reserve_dir {
+        config.reserve_dir = Some(v);
+    }
+    if let Some(v) = cli.reserve_for {
+        config.reserve_for = v;
+    }
+    if let Some(v) = cli.usage_ledger {
+        config.usage_ledger = Some(v);
+    }
+    config
+        .nvidia_smi_extra_args
+        .extend(cli.nvidia_smi_extra_args);
+    if let Some(v) = cli.drain_file {
+        config.drain_file = Some(v);
+    }
+    if let Some(v) = cli.control_file {
+        config.control_file = Some(v);
+    }
+    if cli.yield_on_drain {
+        config.yield_on_drain = true;
+    }
+    if let Some(v) = cli.yield_on_file {
+        config.yield_on_file = Some(v);
+    }
+    if cli.yield_on_foreign_process {
+        config.yield_on_foreign_process = true;
+    }
+    if let Some(v) = cli.yield_grace {
+        config.yield_grace = v;
+    }
+    for x in cli.warn_thresholds {
+        match x {
+            WarnThreshold::Memory(v) => config.warn_memory_free = Some(v),
+            WarnThreshold::Gpu(v) => config.warn_gpu_free = Some(v),
+        }
+    }
+    if let Some(v) = cli.set_envs {
+        merge_envs(&mut config.set_envs, v);
+    }
+    if let Some(v) = cli.unset_envs {
+        merge_envs(&mut config.unset_envs, v);
+    }
+    if cli.strict_env_expansion {
+        config.strict_env_expansion = true;
+    }
+
+    // config operations complete whether a command is given or not, only its
+    // execution is skipped without one
+    if cli.print_config {
+        println!(
+            "Current config:\n{}",
+            to_config_format_or_exit(&config, cli.format).trim_end()
+        );
+    } else {
+        info!("Current config:\n{}", to_json_for_log(&config));
+    }
+
+    if cli.save_config
+        && let Err(e) = save_config(&config, config_path, cli.create_dirs)
+    {
+        let hint = match e.kind() {
+            std::io::ErrorKind::NotFound if !cli.create_dirs => {
+                ", create its directory or pass --create-dirs"
+            }
+            std::io::ErrorKind::PermissionDenied => ", choose a writable path with --config-path",
+            _ => "",
+        };
+        error!(
+            "Save config to {} failed: {}{}",
+            config_path.display(),
+            e,
+            hint
+        );
+        exit(2);
+    }
+    if let Some(Commands::Config { .. }) = cli.command {
+        return;
+    }
+
+    if let Some(Commands::Locks { action }) = &cli.command {
+        let Some(dir) = &config.reserve_dir else {
+            error!("No reserve dir, set it with --reserve-dir or reserve_dir in the config");
+            exit(1);
+        };
+        match action {
+            LocksAction::List => list_locks(dir),
+            LocksAction::Clean { force } => clean_locks(dir, *force),
+        }
+        return;
+    }
+    if let Some(Commands::Bench {
+        gpus,
+        iterations,
+        query,
+    }) = &cli.command
+    {
+        if *query {
+            bench_queries(&config, *iterations);
+        } else {
+            bench_parsing(*gpus, *iterations, &config.filter_fields);
+        }
+        return;
+    }
+    if let Some(Commands::Doctor { json_output }) = &cli.command {
+        if !doctor(&config, *json_output) {
+            exit(1);
+        }
+        return;
+    }
+    if let Some(Commands::Status) = cli.command {
+        if !print_status(&config) {
+            exit(1);
+        }
+        return;
+    }
+
+    // after saving, not to replace core_count in the file
+    if !config.wait_for_indices.is_empty() {
+        config.wait_for_indices.sort_unstable();
+        config.wait_for_indices.dedup();
+        config.core_count = config.wait_for_indices.len();
+    }
+    // after saving, not to replace memory_per_core in the file
+    if memory_by_count && let Some(v) = memory_for_core_count(&config) {
+        info!(
+            "memory_per_core is {} G for {} GPU(s) from memory_per_core_by_count",
+            v, config.core_count
+        );
+        config.memory_per_core = v;
+    }
+    // after looking up memory_per_core, which is per task
+    if !cli.tasks.is_empty() {
+        config.core_count *= cli.tasks.len();
+    }
+
+    // after saving, the file keeps gpu_env unset for the backend to decide
+    let backend = config.backend.gpu_backend();
+    if config.gpu_env == default_config().gpu_env {
+        config.gpu_env = backend.default_gpu_env().to_string();
+    }
+    if config.backend == Backend::Amd {
+        if config.require_exclusive || config.yield_on_foreign_process {
+            warn!(
+                "rocm-smi doesn't report the processes of each GPU, require_exclusive and yield_on_foreign_process see none"
+            );
+        }
+        if config.spread_pcie {
+            warn!("rocm-smi doesn't report the PCIe topology, spread_pcie is ignored");
+        }
+        if !config.nvidia_smi_extra_args.is_empty() {
+            warn!("nvidia_smi_extra_args are ignored with the amd backend");
+        }
+        if !config.filter_fields.is_empty() {
+            error!("filter_fields are nvidia-smi query fields, not supported with the amd backend");
+            exit(1);
+        }
+    }
+
+    // checked before waiting, run_command expands them again against the same
+    // environment
+    if let Err(e) = expand_set_envs(&config.set_envs, config.strict_env_expansion) {
+        error!("set_envs: {}", e);
+        exit(1);
+    }
+
+    if let Err(e) = check_nvidia_smi_extra_args(&config.nvidia_smi_extra_args) {
+        error!("nvidia_smi_extra_args: {}", e);
+        exit(1);
+    }
+
+    if let Some(strategy) = cli.explain_strategy {
+        print!("{}", explain_strategy(strategy, &config));
+        return;
+    }
+
+    if let Some(socket) = &cli.daemon {
+        serve_daemon(socket, &config);
+        return;
+    }
+
+    if cli.dump_nvidia_smi {
+        match query_gpus(
+            config.backend,
+            &Node::Local,
+            &config.nvidia_smi_extra_args,
+            &config.filter_fields,
+        ) {
+            Ok(v) => {
+                println!(
+                    "{} {}",
+                    config.backend.gpu_backend().tool(),
+                    v.args.join(" ")
+                );
+                print!("{}", v.output);
+            }
+            Err(e) => exit_nvidia_smi_error(&e),
+        }
+        return;
+    }
+
+    if !executes {
+        if !cli.print_config && !cli.save_config {
+            Cli::command().print_help().unwrap();
+        }
+        return;
+    }
+
+    if let Some(v) = &config.finally {
+        install_finally(v);
+    }
+    install_signal_forwarding();
+    if cli.poll_immediately_on_signal {
+        install_poll_signal();
+    }
+    if config.strategy == Strategy::LeastRecentlyUsed && config.usage_ledger.is_none() {
+        config.usage_ledger = Some(usage_ledger_path(config_path));
+    }
+    if let Some(v) = &config.min_driver_version {
+        if config.backend == Backend::Nvidia {
+            check_driver_version(v);
+        } else {
+            warn!("min_driver_version is ignored with the amd backend");
+        }
+    }
+    // checked before waiting, not to fail once the GPUs are free
+    let cgroup = config.cgroup.as_deref().map(|x| match open_cgroup(x) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("{}", e);
+            exit(1);
+        }
+    });
+    let secret_envs = match &config.secret_env_file {
+        // the values would be on the ssh command line, visible to everyone
+        Some(_) if !config.nodes.is_empty() => {
+            error!("secret_env_file only applies to local commands, not with nodes");
+            exit(1);
+        }
+        Some(path) => read_secret_envs(path).unwrap_or_else(|e| {
+            error!("{}", e);
+            exit(1);
+        }),
+        None => vec![],
+    };
+
+    // without a shell, the prefix is split into words to prepend to argv
+    let argv: Vec<String> = config
+        .command_prefix
+        .iter()
+        .flat_map(|x| x.split_whitespace().map(String::from))
+        .chain(cli.cmd.iter().cloned())
+        .collect();
+    let cmd = if config.shell {
+        match &config.command_prefix {
+            Some(prefix) if !prefix.trim().is_empty() => {
+                format!("{} {}", prefix, cli.cmd.join(" "))
+            }
+            _ => cli.cmd.join(" "),
+        }
+    } else {
+        Program::Exec(argv.clone()).to_string()
+    };
+
+    if cli.dry_run {
+        if !dry_run(&config, &cmd, &secret_envs, cli.output) {
+            exit(1);
+        }
+        return;
+    }
+    // too long to spawn is known before waiting, not only after the GPUs are reserved
+    if !cli.tasks.is_empty() {
+        for task in &cli.tasks {
+            check_command_length(&[task], false, cli.strict_arg_max);
+        }
+    } else if config.shell {
+        check_command_length(&[&cmd], true, cli.strict_arg_max);
+    } else {
+        let args: Vec<&str> = argv.iter().map(String::as_str).collect();
+        check_command_length(&args, false, cli.strict_arg_max);
+    }
+
+    #[cfg(feature = "otel")]
+    let tracer = cli.otel_endpoint.as_deref().map(otel::Tracer::new);
+    #[cfg(feature = "otel")]
+    let mut wait_span = tracer.as_ref().map(|t| t.span("wait", None));
+
+    let state_path = last_selection_path(config_path);
+    if config.daemon_socket.is_some() && !config.nodes.is_empty() {
+        warn!("The daemon only serves its local GPUs, nodes are ignored");
+    }
+    if cli.reuse_last && !config.nodes.is_empty() {
+        warn!("--reuse-last only applies to the local GPUs, ignored with nodes");
+    }
+    // a pause holds the reused GPUs back as well, the wait checks them again
+    let paused = config.control_file.as_ref().is_some_and(|x| x.exists());
+    let reused = if cli.reuse_last && config.nodes.is_empty() && !paused {
+        check_drain(&config);
+        reuse_last_selection(&state_path, &config)
+    } else {
+        None
+    };
+    // the reused GPUs still have to be reserved like freshly selected ones
+    let reused = reused.and_then(|gpus| match acquire_reservation(&config, &gpus) {
+        Ok(reservation) => Some(Acquisition {
+            gpus,
+            polls: 1,
+            waited: Duration::ZERO,
+            reservation,
+        }),
+        Err(index) => {
+            info!(
+                "Last selected GPU {} is reserved by another instance",
+                index
+            );
+            None
+        }
+    });
+    let mut reused = reused;
+    let mut waiting = serde_json::json!({"core_count": config.core_count});
+    if !config.nodes.is_empty() {
+        waiting["nodes"] = config.nodes.clone().into();
+    }
+    emit_event("waiting", waiting);
+    let (node, mut acquisition) = loop {
+        let (node, acquisition) = match reused.take() {
+            Some(v) => (Node::Local, v),
+            None => wait_on_nodes(&config, cli.print_selection_json),
+        };
+        match &config.soak_command {
+            Some(soak) if !soak_passed(soak, &node, &acquisition.gpus, &config, &secret_envs) => {
+                if let Some(v) = acquisition.reservation {
+                    v.release();
+                }
+                let until = Instant::now() + Duration::from_secs(config.soak_cooldown);
+                config
+                    .soak_failed
+                    .extend(acquisition.gpus.iter().map(|x| (node.clone(), *x, until)));
+            }
+            _ => break (node, acquisition),
+        }
+    };
+    if config.sort_output {
+        acquisition.gpus.sort_unstable();
+    }
+    if cli.reuse_last {
+        save_last_selection(&acquisition.gpus, &state_path);
+    }
+    let gpus = acquisition.gpu_list();
+    check_gpu_env_limits(&config, &acquisition.gpus, &gpus);
+    let mut acquired = serde_json::json!({
+        "gpus": acquisition.gpus,
+        "gpu_env": config.gpu_env,
+        "checks": acquisition.polls,
+        "waited_ms": acquisition.waited.as_millis() as u64,
+    });
+    if let Node::Ssh(host) = &node {
+        acquired["node"] = host.as_str().into();
+    }
+    emit_event("acquired", acquired);
+
+    if cli.emit_exports {
+        if !secret_envs.is_empty() {
+            warn!("The variables of secret_env_file aren't printed as exports");
+        }
+        if let Node::Ssh(host) = &node {
+            println!("# node: {}", host);
+        }
+        print!(
+            "{}",
+            format_exports(&gpus, &config.gpu_env, &config.set_envs, &config.unset_envs)
+        );
+        if let Some(v) = acquisition.reservation {
+            v.finish(config.reserve_for);
+        }
+        set_exit_reason("success");
+        return;
+    }
+
+    if let Some(name) = &cli.emit_var {
+        // bare, the GPU list needs no quoting and $GITHUB_ENV takes none
+        println!("{}={}", name, gpus);
+        if let Some(v) = acquisition.reservation {
+            v.finish(config.reserve_for);
+        }
+        set_exit_reason("success");
+        return;
+    }
+
+    if cli.output == OutputFormat::Json {
+        let mut selection = serde_json::json!({
+            "gpus": acquisition.gpus,
+            "gpu_env": config.gpu_env,
+            "checks": acquisition.polls,
+        });
+        if let Node::Ssh(host) = &node {
+            selection["node"] = host.as_str().into();
+        }
+        println!("{}", selection);
+    }
+    if cli.no_exec {
+        if cli.output == OutputFormat::Text {
+            println!("{}", gpus);
+        }
+        if let Some(v) = acquisition.reservation {
+            v.finish(config.reserve_for);
+        }
+        set_exit_reason("success");
+        return;
+    }
+
+    if cli.print_bitmask {
+        if let Some(v) = acquisition.reservation {
+            v.finish(config.reserve_for);
+        }
+        let Some(mask) = gpu_bitmask(&acquisition.gpus, BITMASK_GPUS) else {
+            error!(
+                "GPU {} doesn't fit in the bitmask, only GPUs 0-{} do",
+                gpus,
+                BITMASK_GPUS - 1
+            );
+            exit(1);
+        };
+        println!("{}", mask);
+        set_exit_reason("success");
+        return;
+    }
+
+    if cli.exit_bitmask {
+        if let Some(v) = acquisition.reservation {
+            v.finish(config.reserve_for);
+        }
+        let Some(mask) = gpu_bitmask(&acquisition.gpus, EXIT_BITMASK_GPUS) else {
+            error!(
+                "GPU {} doesn't fit in the exit code, only GPUs 0-{} do",
+                gpus,
+                EXIT_BITMASK_GPUS - 1
+            );
+            exit(1);
+        };
+        set_exit_reason("success");
+        exit(mask as i32);
+    }
+
+    #[allow(unused_mut)]
+    let mut set_envs = config.set_envs.clone();
+    #[cfg(feature = "otel")]
+    let mut run_span = None;
+    #[cfg(feature = "otel")]
+    if let (Some(tracer), Some(span)) = (&tracer, &mut wait_span) {
+        span.end();
+        span.attr_int("wizard.polls", acquisition.polls as i64);
+        span.attr_int("wizard.wait_ms", acquisition.waited.as_millis() as i64);
+        tracer.export(&[span]);
+
+        let mut span = tracer.span("run", None);
+        span.attr_str("wizard.command", &cmd);
+        span.attr_str("wizard.gpus", &gpus);
+        set_envs.push(format!("TRACEPARENT={}", tracer.traceparent(&span)));
+        run_span = Some(span);
+    }
+    info!(
+        polls = acquisition.polls,
+        waited_ms = acquisition.waited.as_millis() as u64,
+        gpus = gpus.as_str();
+        "Resource is acquired after {} check(s) in {:.1} s",
+        acquisition.polls,
+        acquisition.waited.as_secs_f64()
+    );
+    if config.confirm_when_tight
+        && in_terminal_foreground()
+        && !confirm_tight_gpus(&config, &node, &acquisition.gpus)
+    {
+        if let Some(v) = acquisition.reservation {
+            v.release();
+        }
+        set_exit_reason("declined");
+        exit(1);
+    }
+
+    let options = RunOptions {
+        profile: cli
+            .profile_output
+            .as_deref()
+            .map(|x| (x, Duration::from_secs_f64(cli.profile_interval))),
+        yield_on: YieldOn::from_config(&config, &node),
+        warn_on: WarnOn::from_config(&config, &node),
+        node: node.clone(),
+        cgroup,
+        secret_envs,
+        start_banner: config
+            .start_banner
+            .as_deref()
+            .unwrap_or(DEFAULT_START_BANNER),
+        stop_banner: config.stop_banner.as_deref().unwrap_or(DEFAULT_STOP_BANNER),
+        banners_to_stderr: cli.output == OutputFormat::Json,
+    };
+    // recorded on start for the instances selecting meanwhile, and on stop
+    if let Some(path) = &config.usage_ledger {
+        record_usage(path, &node, &acquisition.gpus);
+    }
+    #[cfg(feature = "notify")]
+    let run_started = Instant::now();
+    let code = if cli.tasks.is_empty() {
+        // the environment is only applied to the local ssh, so pass it in the script
+        let program = match &node {
+            Node::Local if config.shell => Program::Shell(cmd),
+            Node::Local => Program::Exec(argv),
+            // the quoted argv reaches the remote shell as literal words
+            Node::Ssh(host) => {
+                Program::Shell(remote_command(host, &cmd, &gpus, &config, &set_envs))
+            }
+        };
+        run_command(
+            &program,
+            &gpus,
+            &config.gpu_env,
+            &set_envs,
+            &config.unset_envs,
+            &config.env_passthrough,
+            options,
+        )
+    } else {
+        run_tasks(&cli.tasks, &config, &acquisition.gpus, &set_envs, options)
+    };
+    if let Some(path) = &config.usage_ledger {
+        record_usage(path, &node, &acquisition.gpus);
+    }
+    if let Some(v) = acquisition.reservation {
+        v.finish(config.reserve_for);
+    }
+
+    #[cfg(feature = "notify")]
+    if cli.notify_on_complete {
+        let cmd = if cli.tasks.is_empty() {
+            cli.cmd.join(" ")
+        } else {
+            format!("{} tasks", cli.tasks.len())
+        };
+        notify::send(
+            &format!("{} exited with {}", cmd, code),
+            &format!(
+                "GPU {} on {}, ran {} s",
+                gpus,
+                node,
+                run_started.elapsed().as_secs()
+            ),
+        );
+    }
+
+    #[cfg(feature = "otel")]
+    if let (Some(tracer), Some(span)) = (&tracer, &mut run_span) {
+        span.end();
+        span.attr_int("wizard.exit_code", code as i64);
+        tracer.export(&[span]);
+    }
+    set_exit_reason(if INTERRUPTED.load(Ordering::Relaxed) {
+        "signal"
+    } else if code == 0 {
+        "success"
+    } else {
+        "failure"
+    });
+    exit(code);
+}
+
+/// Whether the command is given but only whitespace, or one of the tasks is.
+/// `sh -c ""` would succeed without running anything, i.e. for an unset
+/// variable in a script.
+fn has_blank_command(cmd: &[String], tasks: &[String]) -> bool {
+    !cmd.is_empty() && cmd.iter().all(|x| x.trim().is_empty())
+        || tasks.iter().any(|x| x.trim().is_empty())
+}
+
+/// The config operations asked for, `(print, save)`, by the deprecated flags
+/// or the `config` subcommand, which prints unless it only saves.
+fn config_operations(cli: &Cli) -> (bool, bool) {
+    match cli.command {
+        Some(Commands::Config { print, save }) => (print || !save, save),
+        _ => (cli.print_config, cli.save_config),
+    }
+}
+
+/// Whether a command, tasks or one of their stand-ins is given, i.e. whether
+/// anything is left to do after the config operations.
+fn has_execution(cli: &Cli) -> bool {
+    !cli.cmd.is_empty()
+        || !cli.tasks.is_empty()
+        || cli.emit_exports
+        || cli.emit_var.is_some()
+        || cli.print_bitmask
+        || cli.exit_bitmask
+        || cli.no_exec
+        || cli.dry_run
+}
+
+/// The long flag of the argument `id`, so that the examples break loudly
+/// instead of going stale when a flag is renamed.
+fn flag(id: &str) -> String {
+    let command = Cli::command();
+    let arg = command
+        .get_arguments()
+        .find(|x| x.get_id() == id)
+        .unwrap_or_else(|| panic!("unknown argument {}", id));
+    format!("--{}", arg.get_long().unwrap())
+}
+
+/// `--help` followed by worked examples, built from the actual flags and defaults.
+fn verbose_help() -> String {
+    let name = env!("CARGO_PKG_NAME");
+    let defaults = default_config();
+    let mut out = Cli::command().render_long_help().to_string();
+
+    out.push_str("\nExamples:\n");
+    let examples = [
+        (
+            "Wait for 2 GPUs with 10 G free memory and 80 % free utilization each",
+            format!(
+                "{} {} 2 {} 10 {} 80 python train.py",
+                name,
+                flag("core_count"),
+                flag("memory_per_core"),
+                flag("gpu_percent")
+            ),
+        ),
+        (
+            "Only trust GPUs that stayed free for 3 checks, 30 seconds apart",
+            format!(
+                "{} {} 3 {} 30 python train.py",
+                name,
+                flag("check_times"),
+                flag("check_interval")
+            ),
+        ),
+        (
+            "Read the thresholds from a config file, and save the current ones",
+            format!(
+                "{} {} jobs/plan.json python train.py\n    {} {} 4 {} {} jobs/plan.json",
+                name,
+                flag("config_path"),
+                name,
+                flag("core_count"),
+                flag("save_config"),
+                flag("config_path")
+            ),
+        ),
+        (
+            "Pass options to the command after `--`",
+            format!(
+                "{} {} 1 -- python train.py --lr 0.1",
+                name,
+                flag("core_count")
+            ),
+        ),
+        (
+            "Select in the current shell instead of running a command",
+            format!(
+                "eval \"$({} {} 2 {})\"",
+                name,
+                flag("core_count"),
+                flag("emit_exports")
+            ),
+        ),
+    ];
+    for (what, example) in examples {
+        out.push_str(&format!("  {}:\n    {}\n", what, example));
+    }
+
+    out.push_str(&format!("\nStrategies ({}):\n", flag("strategy")));
+    for strategy in Strategy::value_variants() {
+        let value = strategy.to_possible_value().unwrap();
+        let default = if *strategy == defaults.strategy {
+            " (default)"
+        } else {
+            ""
+        };
+        out.push_str(&format!(
+            "  {}{}: {}\n",
+            value.get_name(),
+            default,
+            value.get_help().map(|x| x.to_string()).unwrap_or_default()
+        ));
+    }
+
+    out.push_str(&format!(
+        "\nDefaults:\n  Options not given on the command line are read from the config file ({} or .plan.json),\n  then from the node config (${} or {}),\n  otherwise core_count={}, memory_per_core={} G, gpu_percent={} %, check_times={},\n  check_interval={} s, gpu_env={}.\n",
+        flag("config_path"),
+        NODE_CONFIG_ENV,
+        NODE_CONFIG_PATH,
+        defaults.core_count,
+        defaults.memory_per_core,
+        defaults.gpu_percent,
+        defaults.check_times,
+        defaults.check_interval,
+        defaults.gpu_env
+    ));
+
+    out.push_str(&format!(
+        "\nExit codes:\n  The exit code of the command, or 128 + N if it was killed by signal N.\n  1 if the configuration, the driver or the GPU count is unsuitable ({}, {}).\n  2 if the arguments are invalid.\n  {} if nvidia-smi is missing, fails on the first check or prints an unexpected output.\n  {} if the drain file exists while waiting ({}).\n  {} if the resource isn't enough within {}.\n  With {} the selection is printed instead, the exit code is the usual one.\n  With {} the selection instead, bit i set for GPU i.\n",
+        flag("min_driver_version"),
+        flag("strict_gpu_count"),
+        EXIT_NVIDIA_SMI,
+        EXIT_DRAINED,
+        flag("drain_file"),
+        EXIT_TIMEOUT,
+        flag("max_wait"),
+        flag("print_bitmask"),
+        flag("exit_bitmask")
+    ));
+
+    out.push_str(&format!(
+        "\nEnvironment:\n  The command gets the selected GPUs in gpu_env (default {}), the variables of set_envs\n  and none of unset_envs. With {} only the matching variables are passed on.\n  RUST_LOG sets the log level unless {} is given.\n  {} runs its command with WIZARD_EVENT, WIZARD_GPU and WIZARD_MESSAGE set.\n  {} runs its command with WIZARD_EXIT_REASON set to success, failure, signal,\n  drained, timeout, declined, panic or error.\n",
+        defaults.gpu_env,
+        flag("env_passthrough"),
+        flag("verbose"),
+        flag("notify_command"),
+        flag("finally")
+    ));
+    out
+}
+
+/// The GPUs the bitmask of `--print-bitmask` can tell.
+const BITMASK_GPUS: usize = u128::BITS as usize;
+
+/// The GPUs the exit code of `--exit-bitmask` can tell, the status of a
+/// process keeps 8 bits.
+const EXIT_BITMASK_GPUS: usize = 8;
+
+/// The mask with bit i set for each selected GPU i, `None` if one of them
+/// isn't below `bits`.
+fn gpu_bitmask(gpus: &[usize], bits: usize) -> Option<u128> {
+    gpus.iter()
+        .try_fold(0, |mask, &gpu| (gpu < bits).then(|| mask | 1 << gpu))
+}
+
+/// Where the GPUs are queried and the command runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Local,
+    /// A host reached with `ssh`, which has to log in without a prompt.
+    Ssh(String),
+}
+
+impl Node {
+    /// A command running `program` on this node, taking further arguments.
+    fn command(&self, program: &str) -> Command {
+        match self {
+            Node::Local => Command::new(program),
+            Node::Ssh(host) => {
+                let mut command = Command::new("ssh");
+                command.args(["-o", "BatchMode=yes", host, program]);
+                command
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Node::Local => write!(f, "localhost"),
+            Node::Ssh(host) => write!(f, "{}", host),
+        }
+    }
+}
+
+/// Run the command on `host` through ssh, with the environment exported by the
+/// remote shell and in the same working directory if it exists there.
+fn remote_command(
+    host: &str,
+    cmd: &str,
+    gpus: &str,
+    config: &Config,
+    set_envs: &[String],
+) -> String {
+    let mut script = format_exports(gpus, &config.gpu_env, set_envs, &config.unset_envs);
+    if let Ok(dir) = env::current_dir() {
+        script.push_str(&format!(
+            "cd {} 2>/dev/null\n",
+            shell_quote(&dir.to_string_lossy())
+        ));
+    }
+    script.push_str(cmd);
+    format!("ssh {} {}", shell_quote(host), shell_quote(&script))
+}
+
+/// Why nvidia-smi (or rocm-smi with the amd backend) gave no GPU list. Each
+/// variant but `NotFound` names the tool first.
+#[derive(Debug)]
+enum NvidiaSmiError {
+    /// The program (the tool, or ssh for a node) isn't on PATH.
+    NotFound(String),
+    Spawn(&'static str, std::io::Error),
+    /// It exited with an error, with the first line of its message.
+    Failed(&'static str, ExitStatus, String),
+    /// The output lacks the queried fields.
+    Parse(&'static str, String),
+}
+
+impl std::fmt::Display for NvidiaSmiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NvidiaSmiError::NotFound(program) => write!(f, "{} not found on PATH", program),
+            NvidiaSmiError::Spawn(tool, e) => write!(f, "{} execute failed: {}", tool, e),
+            NvidiaSmiError::Failed(tool, status, msg) => write!(f, "{} {}: {}", tool, status, msg),
+            NvidiaSmiError::Parse(tool, msg) => write!(f, "unexpected {} output: {}", tool, msg),
+        }
+    }
+}
+
+/// The exit code when nvidia-smi is missing or doesn't work.
+const EXIT_NVIDIA_SMI: i32 = 3;
+
+fn exit_nvidia_smi_error(e: &NvidiaSmiError) -> ! {
+    error!("{}", e);
+    exit(EXIT_NVIDIA_SMI);
+}
+
+/// Run nvidia-smi on `node` and return its output.
+fn run_nvidia_smi(node: &Node, args: &[String]) -> Result<String, NvidiaSmiError> {
+    run_gpu_tool(node, "nvidia-smi", args)
+}
+
+/// Run `tool` on `node` and return its output.
+fn run_gpu_tool(
+    node: &Node,
+    tool: &'static str,
+    args: &[String],
+) -> Result<String, NvidiaSmiError> {
+    let mut command = node.command(tool);
+    let output = command.args(args).output().map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            NvidiaSmiError::NotFound(command.get_program().to_string_lossy().to_string())
+        } else {
+            NvidiaSmiError::Spawn(tool, e)
+        }
+    })?;
+    if !output.status.success() {
+        // nvidia-smi reports some errors, i.e. without a driver, on stdout
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let msg = stderr
+            .lines()
+            .chain(stdout.lines())
+            .find(|x| !x.trim().is_empty())
+            .unwrap_or("")
+            .trim()
+            .to_string();
+        return Err(NvidiaSmiError::Failed(tool, output.status, msg));
+    }
+    Ok(decode_output(output.stdout))
+}
+
+fn parse_cuda_info(config: &Config, node: &Node) -> Result<Vec<GPUInfo>, NvidiaSmiError> {
+    let mut gpu_info_list = vec![];
+    parse_cuda_info_into(config, node, &mut gpu_info_list)?;
+    Ok(gpu_info_list)
+}
+
+/// Like [`parse_cuda_info`], but reuse `gpu_info_list` and the allocations of
+/// its GPUs, which the wait loop keeps across checks.
+fn parse_cuda_info_into(
+    config: &Config,
+    node: &Node,
+    gpu_info_list: &mut Vec<GPUInfo>,
+) -> Result<(), NvidiaSmiError> {
+    let backend = config.backend.gpu_backend();
+    read_gpus(config, node, gpu_info_list)?;
+    let mut samples = vec![];
+    for _ in 1..config.sample_count.max(1) {
+        sleep(Duration::from_millis(config.sample_spacing));
+        let mut sample = vec![];
+        read_gpus(config, node, &mut sample)?;
+        samples.push(sample);
+    }
+    average_samples(gpu_info_list, &samples);
+    if !config.allow_self_stacking || config.require_exclusive {
+        backend.attach_processes(node, gpu_info_list);
+    }
+    if config.spread_pcie {
+        backend.attach_pcie_roots(node, gpu_info_list);
+    }
+    if let Some(path) = &config.usage_ledger {
+        attach_last_use(path, node, gpu_info_list);
+    }
+    info!(gpus = gpu_info_list.len(); "{:?}", gpu_info_list);
+    Ok(())
+}
+
+/// One reading of the GPUs of `node` into `gpu_info_list`, before any
+/// attachments.
+fn read_gpus(
+    config: &Config,
+    node: &Node,
+    gpu_info_list: &mut Vec<GPUInfo>,
+) -> Result<(), NvidiaSmiError> {
+    // NVML has no generic field query
+    #[cfg(feature = "nvml")]
+    if config.nvml
+        && config.backend == Backend::Nvidia
+        && *node == Node::Local
+        && config.filter_fields.is_empty()
+    {
+        match nvml::shared() {
+            Ok(nvml) => match read_nvml_into(nvml, gpu_info_list) {
+                Ok(()) => {
+                    apply_memory_metric(config.memory_metric, gpu_info_list);
+                    return Ok(());
+                }
+                Err(e) => warn!("NVML query failed, using nvidia-smi: {}", e),
+            },
+            Err(e) => {
+                static WARNED: AtomicBool = AtomicBool::new(false);
+                if !WARNED.swap(true, Ordering::Relaxed) {
+                    warn!("NVML can't be loaded, using nvidia-smi: {}", e);
+                }
+            }
+        }
+    }
+    let query = query_gpus(
+        config.backend,
+        node,
+        &config.nvidia_smi_extra_args,
+        &config.filter_fields,
+    )?;
+    config
+        .backend
+        .gpu_backend()
+        .parse_into(&query, gpu_info_list)?;
+    apply_memory_metric(config.memory_metric, gpu_info_list);
+    Ok(())
+}
+
+/// Replace `memory_free` by `memory_total - memory_used` with the computed
+/// metric, where the used memory is known.
+fn apply_memory_metric(metric: MemoryMetric, gpu_info_list: &mut [GPUInfo]) {
+    if metric != MemoryMetric::Computed {
+        return;
+    }
+    for gpu_info in gpu_info_list.iter_mut() {
+        if let Some(used) = gpu_info.memory_used {
+            gpu_info.memory_free = gpu_info.memory_total.saturating_sub(used);
+        }
+    }
+}
+
+/// Read the GPUs through NVML into `gpu_info_list`, with the same fields and
+/// units as [`parse_cuda_output_into`].
+#[cfg(feature = "nvml")]
+fn read_nvml_into(nvml: &nvml::Nvml, gpu_info_list: &mut Vec<GPUInfo>) -> Result<(), String> {
+    let mut parsed = 0;
+    nvml.read(|x| {
+        let gpu_info = next_gpu_info(gpu_info_list, &mut parsed, x.index, x.uuid);
+        fill_from_nvml(gpu_info, &x);
+    })?;
+    gpu_info_list.truncate(parsed);
+    Ok(())
+}
+
+/// Fill `gpu_info`, its index and uuid set already, from the NVML `reading`.
+#[cfg(feature = "nvml")]
+fn fill_from_nvml(gpu_info: &mut GPUInfo, reading: &nvml::Reading<'_>) {
+    gpu_info.gpu_free = 100usize.saturating_sub(reading.gpu_percent);
+    gpu_info.memory_free = (reading.memory_free >> 20) as u32;
+    gpu_info.memory_total = (reading.memory_total >> 20) as u32;
+    gpu_info.memory_used = Some((reading.memory_used >> 20) as u32);
+    gpu_info.persistence_mode = reading.persistence_mode;
+    gpu_info.ecc_errors = reading.ecc_errors;
+    gpu_info.temperature = reading.temperature;
+    gpu_info.power_limit = reading.power_limit;
+    gpu_info.power_draw = reading.power_draw;
+}
+
+/// Options owning the query and its output format, which the parser relies on.
+const NVIDIA_SMI_RESERVED_ARGS: [&str; 10] = [
+    "--query-gpu",
+    "--format",
+    "-q",
+    "--query",
+    "-x",
+    "--xml-format",
+    "-f",
+    "--filename",
+    "-l",
+    "--loop",
+];
+
+/// Reject extra nvidia-smi arguments that would change the query or its
+/// output format.
+fn check_nvidia_smi_extra_args(extra_args: &[String]) -> Result<(), String> {
+    for arg in extra_args {
+        let name = arg.split('=').next().unwrap_or(arg);
+        if NVIDIA_SMI_RESERVED_ARGS.contains(&name) || name.starts_with("--query-") {
+            return Err(format!("{} conflicts with the GPU query", arg));
+        }
+    }
+    Ok(())
+}
+
+/// The fields of the GPU query parsed into [`GPUInfo`], in the order queried.
+/// Every driver supports the first [`REQUIRED_QUERY_FIELDS`], the others are
+/// left out of the query where the driver rejects them.
+const QUERY_FIELDS: [&str; 11] = [
+    "index",
+    "uuid",
+    "utilization.gpu",
+    "memory.free",
+    "memory.total",
+    "persistence_mode",
+    "ecc.errors.uncorrected.aggregate.total",
+    "temperature.gpu",
+    "memory.used",
+    "power.limit",
+    "power.draw",
+];
+const REQUIRED_QUERY_FIELDS: usize = 5;
+
+/// The optional query fields the driver of each node rejected.
+static UNSUPPORTED_FIELDS: Mutex<Vec<(Node, &str)>> = Mutex::new(vec![]);
+
+/// The GPU query of `fields`, then the fields of `filters`.
+fn nvidia_smi_args(fields: &[&str], extra_args: &[String], filters: &[FieldFilter]) -> Vec<String> {
+    let mut query = fields.join(",");
+    for filter in filters {
+        query.push(',');
+        query.push_str(&filter.field);
+    }
+    let mut args: Vec<String> = vec![
+        "--query-gpu".to_string(),
+        query,
+        "--format".to_string(),
+        "csv,noheader,nounits".to_string(),
+    ];
+    args.extend_from_slice(extra_args);
+    args
+}
+
+/// The optional field of `fields` that nvidia-smi names in `msg` as not
+/// valid to query.
+fn rejected_field(msg: &str, fields: &[&'static str]) -> Option<&'static str> {
+    let name = msg.strip_prefix("Field \"")?.split('"').next()?;
+    fields[REQUIRED_QUERY_FIELDS..]
+        .iter()
+        .find(|x| **x == name)
+        .copied()
+}
+
+/// The output of a GPU query, with what produced it.
+struct GpuQuery {
+    args: Vec<String>,
+    /// The queried fields parsed into [`GPUInfo`], the values of the filters
+    /// follow them.
+    fields: Vec<&'static str>,
+    /// The count of the filter fields queried.
+    filters: usize,
+    output: String,
+}
+
+/// Run the GPU query of `backend` on `node`.
+fn query_gpus(
+    backend: Backend,
+    node: &Node,
+    extra_args: &[String],
+    filters: &[FieldFilter],
+) -> Result<GpuQuery, NvidiaSmiError> {
+    backend.gpu_backend().query(node, extra_args, filters)
+}
+
+/// Reads the GPUs with the tool of one vendor. The selection only sees the
+/// [`GPUInfo`] list, whatever tool filled it.
+trait GpuBackend {
+    fn tool(&self) -> &'static str;
+    /// Run the GPU query on `node`, with the extra arguments of the config and
+    /// the fields of its filters.
+    fn query(
+        &self,
+        node: &Node,
+        extra_args: &[String],
+        filters: &[FieldFilter],
+    ) -> Result<GpuQuery, NvidiaSmiError>;
+    /// Parse the output of the query into `gpu_info_list`, reusing its GPUs.
+    fn parse_into(
+        &self,
+        query: &GpuQuery,
+        gpu_info_list: &mut Vec<GPUInfo>,
+    ) -> Result<(), NvidiaSmiError>;
+    /// The variable the command reads the visible GPUs from.
+    fn default_gpu_env(&self) -> &'static str;
+    /// Fill `processes` of each GPU, a tool that can't tell leaves them empty.
+    fn attach_processes(&self, _node: &Node, _gpu_info_list: &mut [GPUInfo]) {}
+    /// Fill `pcie_root` of each GPU, a tool that can't tell leaves them unset.
+    fn attach_pcie_roots(&self, _node: &Node, _gpu_info_list: &mut [GPUInfo]) {}
+}
+
+struct NvidiaSmi;
+
+struct RocmSmi;
+
+impl Backend {
+    fn gpu_backend(self) -> &'static dyn GpuBackend {
+        match self {
+            Backend::Nvidia => &NvidiaSmi,
+            Backend::Amd => &RocmSmi,
+        }
+    }
+}
+
+impl GpuBackend for NvidiaSmi {
+    fn tool(&self) -> &'static str {
+        "nvidia-smi"
+    }
+
+    /// A field the driver rejects, i.e. the ECC or power ones on some boards,
+    /// is left out of this and the later queries of `node`.
+    fn query(
+        &self,
+        node: &Node,
+        extra_args: &[String],
+        filters: &[FieldFilter],
+    ) -> Result<GpuQuery, NvidiaSmiError> {
+        loop {
+            let fields: Vec<&'static str> = {
+                let unsupported = UNSUPPORTED_FIELDS.lock().unwrap_or_else(|e| e.into_inner());
+                QUERY_FIELDS
+                    .into_iter()
+                    .filter(|x| !unsupported.iter().any(|(n, f)| n == node && f == x))
+                    .collect()
+            };
+            let args = nvidia_smi_args(&fields, extra_args, filters);
+            let e = match run_gpu_tool(node, self.tool(), &args) {
+                Ok(output) => {
+                    return Ok(GpuQuery {
+                        args,
+                        fields,
+                        filters: filters.len(),
+                        output,
+                    });
+                }
+                Err(e) => e,
+            };
+            let NvidiaSmiError::Failed(_, _, msg) = &e else {
+                return Err(e);
+            };
+            let Some(field) = rejected_field(msg, &fields) else {
+                return Err(e);
+            };
+            warn!(
+                "nvidia-smi on {} doesn't support {}, query without it",
+                node, field
+            );
+            UNSUPPORTED_FIELDS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push((node.clone(), field));
+        }
+    }
+
+    fn parse_into(
+        &self,
+        query: &GpuQuery,
+        gpu_info_list: &mut Vec<GPUInfo>,
+    ) -> Result<(), NvidiaSmiError> {
+        parse_cuda_output_into(&query.output, &query.fields, query.filters, gpu_info_list)
+    }
+
+    fn default_gpu_env(&self) -> &'static str {
+        "CUDA_VISIBLE_DEVICES"
+    }
+
+    fn attach_processes(&self, node: &Node, gpu_info_list: &mut [GPUInfo]) {
+        attach_processes(node, gpu_info_list);
+    }
+
+    fn attach_pcie_roots(&self, node: &Node, gpu_info_list: &mut [GPUInfo]) {
+        attach_pcie_roots(node, gpu_info_list);
+    }
+}
+
+impl GpuBackend for RocmSmi {
+    fn tool(&self) -> &'static str {
+        "rocm-smi"
+    }
+
+    /// The extra arguments and filters are for nvidia-smi and aren't passed.
+    fn query(
+        &self,
+        node: &Node,
+        _extra_args: &[String],
+        _filters: &[FieldFilter],
+    ) -> Result<GpuQuery, NvidiaSmiError> {
+        let args = [
+            "--showuse",
+            "--showmeminfo",
+            "vram",
+            "--showtemp",
+            "--showuniqueid",
+            "--showpower",
+            "--showmaxpower",
+            "--json",
+        ]
+        .map(String::from)
+        .to_vec();
+        let output = run_gpu_tool(node, self.tool(), &args)?;
+        Ok(GpuQuery {
+            args,
+            fields: vec![],
+            filters: 0,
+            output,
+        })
+    }
+
+    fn parse_into(
+        &self,
+        query: &GpuQuery,
+        gpu_info_list: &mut Vec<GPUInfo>,
+    ) -> Result<(), NvidiaSmiError> {
+        parse_rocm_output_into(&query.output, gpu_info_list)
+    }
+
+    fn default_gpu_env(&self) -> &'static str {
+        "HIP_VISIBLE_DEVICES"
+    }
+}
+
+/// Decode the output of nvidia-smi, replacing invalid UTF-8 (i.e. from an odd
+/// locale) instead of failing, the fields we parse are ASCII anyway.
+fn decode_output(stdout: Vec<u8>) -> String {
+    String::from_utf8(stdout).unwrap_or_else(|e| {
+        warn!("nvidia-smi output isn't valid UTF-8: {}", e);
+        String::from_utf8_lossy(e.as_bytes()).to_string()
+    })
+}
+
+/// Parse the csv output of nvidia-smi, accepting values with or without units
+/// (i.e. `80` or `80 %`, `2000` or `2000 MiB`) and with thousands separators
+/// (i.e. `40,960 MiB`, quoted or not). `filters` is the count of the filter
+/// values after `fields`.
+fn parse_cuda_output(
+    output: &str,
+    fields: &[&str],
+    filters: usize,
+) -> Result<Vec<GPUInfo>, NvidiaSmiError> {
+    let mut gpu_info_list = vec![];
+    parse_cuda_output_into(output, fields, filters, &mut gpu_info_list)?;
+    Ok(gpu_info_list)
+}
+
+/// Parse the GPUs into `gpu_info_list`, overwriting the GPUs already in it so
+/// that their allocations are reused. `fields` are the queried fields of
+/// [`QUERY_FIELDS`], starting with the required ones, the `filters` values
+/// after them are the ones of the filters. A line with more values than that
+/// is an error, rather than shifting each value into the next field. The
+/// field list of the lines is reused as well.
+fn parse_cuda_output_into(
+    output: &str,
+    fields: &[&str],
+    filters: usize,
+    gpu_info_list: &mut Vec<GPUInfo>,
+) -> Result<(), NvidiaSmiError> {
+    let mut values = Vec::with_capacity(fields.len() + filters);
+    let mut parsed = 0;
+    for line in output.lines() {
+        // a line of spaces
+        if line.trim().is_empty() {
+            continue;
+        }
+        split_csv_line(line, &mut values);
+        let line = line.trim();
+        if values.len() > fields.len() + filters {
+            return Err(NvidiaSmiError::Parse(
+                "nvidia-smi",
+                format!(
+                    "{} fields instead of {} in `{}`",
+                    values.len(),
+                    fields.len() + filters,
+                    line
+                ),
+            ));
+        }
+        let mut field_it = values.iter().copied();
+        let mut next_field = || {
+            field_it.next().ok_or_else(|| {
+                NvidiaSmiError::Parse("nvidia-smi", format!("missing fields in `{}`", line))
+            })
+        };
+        let parse_error =
+            |e: String| NvidiaSmiError::Parse("nvidia-smi", format!("{} in `{}`", e, line));
+        let index = parse_field::<usize>(next_field()?).map_err(parse_error)?;
+        let uuid = next_field()?;
+        let gpu_percent = parse_field::<usize>(next_field()?).map_err(parse_error)?;
+        let memory_free = parse_field::<u32>(next_field()?).map_err(parse_error)?;
+        let memory_total = parse_field::<u32>(next_field()?).map_err(parse_error)?;
+
+        let gpu_info = next_gpu_info(gpu_info_list, &mut parsed, index, uuid);
+        gpu_info.gpu_free = 100usize.saturating_sub(gpu_percent);
+        gpu_info.memory_free = memory_free;
+        gpu_info.memory_total = memory_total;
+        // [N/A] where the driver doesn't support it, and left unset
+        for (&field, value) in fields[REQUIRED_QUERY_FIELDS..].iter().zip(&mut field_it) {
+            match field {
+                "persistence_mode" => gpu_info.persistence_mode = value == "Enabled",
+                "ecc.errors.uncorrected.aggregate.total" => {
+                    gpu_info.ecc_errors = value.parse().ok()
+                }
+                "temperature.gpu" => gpu_info.temperature = parse_field(value).ok(),
+                "memory.used" => gpu_info.memory_used = parse_field(value).ok(),
+                "power.limit" => gpu_info.power_limit = value.parse().ok(),
+                "power.draw" => gpu_info.power_draw = value.parse().ok(),
+                _ => {}
+            }
+        }
+        // the fields of the filters, if any
+        gpu_info
+            .field_values
+            .extend(field_it.map(|x| x.to_string()));
+    }
+    gpu_info_list.truncate(parsed);
+    // nvidia-smi doesn't promise any row order, the selection relies on index order
+    // (i.e. to break ties)
+    gpu_info_list.sort_by_key(|x| x.index);
+    Ok(())
+}
+
+/// Split a line of nvidia-smi csv into `values`, trimmed. nvidia-smi separates
+/// the fields with `, `, so a comma without the space, i.e. of `40,960`, stays
+/// in its field, and a quoted field may hold `, ` as well.
+fn split_csv_line<'a>(line: &'a str, values: &mut Vec<&'a str>) {
+    values.clear();
+    let mut rest = line;
+    loop {
+        let field = rest.trim_start();
+        let (value, next) = match field.strip_prefix('"').and_then(|x| x.split_once('"')) {
+            Some((value, after)) => (value, after.split_once(", ").map(|x| x.1)),
+            None => match field.split_once(", ") {
+                Some((value, next)) => (value.trim_end(), Some(next)),
+                None => (field.trim_end(), None),
+            },
+        };
+        values.push(value);
+        match next {
+            Some(v) => rest = v,
+            None => break,
+        }
+    }
+}
+
+/// The entry of `gpu_info_list` for the next parsed GPU, reused if there is
+/// one, with everything but `index` and `uuid` still to be filled.
+fn next_gpu_info<'a>(
+    gpu_info_list: &'a mut Vec<GPUInfo>,
+    parsed: &mut usize,
+    index: usize,
+    uuid: &str,
+) -> &'a mut GPUInfo {
+    if *parsed == gpu_info_list.len() {
+        gpu_info_list.push(GPUInfo::default());
+    }
+    let gpu_info = &mut gpu_info_list[*parsed];
+    *parsed += 1;
+    gpu_info.index = index;
+    gpu_info.uuid.clear();
+    gpu_info.uuid.push_str(uuid);
+    gpu_info.persistence_mode = false;
+    gpu_info.memory_used = None;
+    gpu_info.ecc_errors = None;
+    gpu_info.temperature = None;
+    gpu_info.power_limit = None;
+    gpu_info.power_draw = None;
+    gpu_info.temperature_rise = None;
+    gpu_info.processes.clear();
+    gpu_info.pcie_root = None;
+    gpu_info.field_values.clear();
+    gpu_info.last_used = None;
+    gpu_info
+}
+
+/// Parse the JSON of `rocm-smi --json`, one object per `cardN` with the
+/// values as strings and the memory in bytes.
+fn parse_rocm_output_into(
+    output: &str,
+    gpu_info_list: &mut Vec<GPUInfo>,
+) -> Result<(), NvidiaSmiError> {
+    let parse_error = |msg: String| NvidiaSmiError::Parse("rocm-smi", msg);
+    let cards: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(output).map_err(|e| parse_error(e.to_string()))?;
+    let mut parsed = 0;
+    for (name, fields) in &cards {
+        // newer versions add a "system" object besides the cards
+        let Some(index) = name.strip_prefix("card") else {
+            continue;
+        };
+        let index =
+            parse_field::<usize>(index).map_err(|e| parse_error(format!("{} in `{}`", e, name)))?;
+        let field = |key: &str| {
+            fields
+                .get(key)
+                .and_then(|x| x.as_str())
+                .ok_or_else(|| parse_error(format!("no `{}` in {}", key, name)))
+        };
+        let gpu_percent = parse_field::<usize>(field("GPU use (%)")?).map_err(parse_error)?;
+        let memory_total =
+            parse_field::<u64>(field("VRAM Total Memory (B)")?).map_err(parse_error)?;
+        let memory_used =
+            parse_field::<u64>(field("VRAM Total Used Memory (B)")?).map_err(parse_error)?;
+        // the sensor names vary by card, the edge one is the closest to temperature.gpu
+        let temperature = fields.as_object().and_then(|x| {
+            x.iter()
+                .find(|(k, _)| k.starts_with("Temperature") && k.contains("edge"))
+                .and_then(|(_, v)| v.as_str())
+                .and_then(|v| parse_field::<u32>(v).ok())
+        });
+        let uuid = fields
+            .get("Unique ID")
+            .and_then(|x| x.as_str())
+            .unwrap_or("");
+        // the names differ between versions as well
+        let power = |prefix: &str| {
+            fields.as_object().and_then(|x| {
+                x.iter()
+                    .find(|(k, _)| k.starts_with(prefix) && k.ends_with("(W)"))
+                    .and_then(|(_, v)| v.as_str())
+                    .and_then(|v| v.trim().parse::<f64>().ok())
+            })
+        };
+        let power_limit = power("Max Graphics Package Power");
+        let power_draw =
+            power("Average Graphics Package Power").or_else(|| power("Current Socket Graphics"));
+
+        let gpu_info = next_gpu_info(gpu_info_list, &mut parsed, index, uuid);
+        gpu_info.gpu_free = 100usize.saturating_sub(gpu_percent);
+        gpu_info.memory_free = (memory_total.saturating_sub(memory_used) >> 20) as u32;
+        gpu_info.memory_total = (memory_total >> 20) as u32;
+        gpu_info.memory_used = Some((memory_used >> 20) as u32);
+        gpu_info.temperature = temperature;
+        gpu_info.power_limit = power_limit;
+        gpu_info.power_draw = power_draw;
+    }
+    gpu_info_list.truncate(parsed);
+    gpu_info_list.sort_by_key(|x| x.index);
+    Ok(())
+}
+
+/// Average utilization and free memory of each GPU over the readings of one
+/// check, so that a momentary dip doesn't decide it. The GPUs of the first
+/// reading, `gpu_info_list`, are kept, each averaged over the readings it
+/// appears in.
+fn average_samples(gpu_info_list: &mut [GPUInfo], samples: &[Vec<GPUInfo>]) {
+    for gpu_info in gpu_info_list.iter_mut() {
+        let readings: Vec<&GPUInfo> = samples
+            .iter()
+            .filter_map(|x| x.iter().find(|g| g.index == gpu_info.index))
+            .collect();
+        let n = readings.len() + 1;
+        gpu_info.gpu_free =
+            (gpu_info.gpu_free + readings.iter().map(|x| x.gpu_free).sum::<usize>()) / n;
+        gpu_info.memory_free =
+            (gpu_info.memory_free + readings.iter().map(|x| x.memory_free).sum::<u32>()) / n as u32;
+    }
+}
+
+/// Parse a numeric field, dropping the unit suffix if there is one. A
+/// fractional part (i.e. `40960.00`) is truncated.
+fn parse_field<T: FromStr>(field: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    let value = field.split_whitespace().next().unwrap_or("");
+    let value = if value.contains(',') {
+        Cow::Owned(value.replace(',', ""))
+    } else {
+        Cow::Borrowed(value)
+    };
+    let value = value.split('.').next().unwrap();
+    value
+        .parse::<T>()
+        .map_err(|e| format!("invalid number `{}` ({})", field.trim(), e))
+}
+
+/// Fill `processes` of each GPU from the compute apps reported by nvidia-smi,
+/// resolving the owner of each process with `ps`.
+fn attach_processes(node: &Node, gpu_info_list: &mut [GPUInfo]) {
+    let output = match node
+        .command("nvidia-smi")
+        .args([
+            "--query-compute-apps",
+            "gpu_uuid,pid",
+            "--format",
+            "csv,noheader,nounits",
+        ])
+        .output()
+    {
+        Ok(v) => String::from_utf8_lossy(&v.stdout).to_string(),
+        Err(e) => {
+            warn!("Query compute apps failed: {}", e);
+            return;
+        }
+    };
+    let mut apps = vec![];
+    for line in output.lines() {
+        if let Some((uuid, pid)) = line.split_once(',')
+            && let Ok(pid) = pid.trim().parse::<u32>()
+        {
+            apps.push((uuid.trim().to_string(), pid));
+        }
+    }
+    if apps.is_empty() {
+        return;
+    }
+
+    let users = process_users(node, &apps.iter().map(|x| x.1).collect::<Vec<u32>>());
+    for (uuid, pid) in apps {
+        if let Some(gpu_info) = gpu_info_list.iter_mut().find(|x| x.uuid == uuid) {
+            gpu_info.processes.push(GPUProcess {
+                pid,
+                user: users.get(&pid).cloned(),
+            });
+        }
+    }
+}
+
+/// Fill `pcie_root` of each GPU from the `nvidia-smi topo -m` matrix. GPUs
+/// connected through a PCIe switch (PIX, PXB) or the same host bridge (PHB)
+/// share a root complex, NODE and SYS cross root complexes. NVLink connections
+/// (NV#) hide the PCIe path, such GPUs count as distinct.
+fn attach_pcie_roots(node: &Node, gpu_info_list: &mut [GPUInfo]) {
+    let output = match node.command("nvidia-smi").args(["topo", "-m"]).output() {
+        Ok(v) => String::from_utf8_lossy(&v.stdout).to_string(),
+        Err(e) => {
+            warn!("Query GPU topology failed: {}", e);
+            return;
+        }
+    };
+    let mut lines = output.lines();
+    let Some(header) = lines.next() else {
+        warn!("Empty GPU topology, GPUs count as distinct root complexes");
+        return;
+    };
+    let columns = header
+        .split_whitespace()
+        .take_while(|x| x.starts_with("GPU"))
+        .count();
+    // union-find over the GPU indices of the matrix
+    let mut parent: Vec<usize> = (0..columns).collect();
+    fn find(parent: &mut [usize], i: usize) -> usize {
+        if parent[i] != i {
+            parent[i] = find(parent, parent[i]);
+        }
+        parent[i]
+    }
+    for line in lines {
+        let mut field_it = line.split_whitespace();
+        let Some(row) = field_it
+            .next()
+            .and_then(|x| x.strip_prefix("GPU"))
+            .and_then(|x| x.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        for (column, link) in field_it.take(columns).enumerate() {
+            if row < columns && matches!(link, "PIX" | "PXB" | "PHB") {
+                let (a, b) = (find(&mut parent, row), find(&mut parent, column));
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    for gpu_info in gpu_info_list.iter_mut() {
+        if gpu_info.index < columns {
+            gpu_info.pcie_root = Some(find(&mut parent, gpu_info.index));
+        }
+    }
+}
+
+/// Map pids to their owners. Processes that can't be seen (i.e. from another
+/// pid namespace) are left out.
+fn process_users(node: &Node, pids: &[u32]) -> HashMap<u32, String> {
+    let pids = pids
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    let output = match node
+        .command("ps")
+        .args(["-o", "pid=,user=", "-p", &pids])
+        .output()
+    {
+        Ok(v) => String::from_utf8_lossy(&v.stdout).to_string(),
+        Err(e) => {
+            warn!("Query process owners failed: {}", e);
+            return HashMap::new();
+        }
+    };
+    let mut users = HashMap::new();
+    for line in output.lines() {
+        let mut field_it = line.split_whitespace();
+        if let (Some(pid), Some(user)) = (field_it.next(), field_it.next())
+            && let Ok(pid) = pid.parse::<u32>()
+        {
+            users.insert(pid, user.to_string());
+        }
+    }
+    users
+}
+
+fn current_user() -> &'static str {
+    static USER: OnceLock<String> = OnceLock::new();
+    USER.get_or_init(|| match env::var("USER") {
+        Ok(v) if !v.is_empty() => v,
+        _ => Command::new("whoami")
+            .output()
+            .map(|x| String::from_utf8_lossy(&x.stdout).trim().to_string())
+            .unwrap_or_default(),
+    })
+}
+
+/// A dotted driver version like `550.54.14`, compared numerically per component.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct DriverVersion(Vec<u32>);
+
+impl FromStr for DriverVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim()
+            .split('.')
+            .map(|x| x.parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map(DriverVersion)
+            .map_err(|_| format!("invalid driver version: {}", s))
+    }
+}
+
+impl std::fmt::Display for DriverVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(|x| x.to_string()).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+fn query_driver_version() -> Result<DriverVersion, NvidiaSmiError> {
+    let output = run_nvidia_smi(
+        &Node::Local,
+        &["--query-gpu", "driver_version", "--format", "csv,noheader"].map(String::from),
+    )?;
+    // every GPU reports the same driver, so the first line is enough
+    let line = output.lines().next().unwrap_or("");
+    line.parse::<DriverVersion>()
+        .map_err(|e| NvidiaSmiError::Parse("nvidia-smi", format!("{} in `{}`", e, line)))
+}
+
+/// The CUDA version supported by the driver, from the header of `nvidia-smi`.
+fn query_cuda_version() -> Result<String, NvidiaSmiError> {
+    let output = run_nvidia_smi(&Node::Local, &[])?;
+    output
+        .split("CUDA Version:")
+        .nth(1)
+        .and_then(|x| x.split_whitespace().next())
+        .map(|x| x.to_string())
+        .ok_or_else(|| {
+            NvidiaSmiError::Parse("nvidia-smi", "no CUDA version in the header".to_string())
+        })
+}
+
+/// Counts the allocations, for [`bench_parsing`]. Only built with the bench
+/// feature, so that other builds don't pay an atomic increment on each
+/// allocation.
+#[cfg(feature = "bench")]
+struct CountingAllocator;
+
+#[cfg(feature = "bench")]
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "bench")]
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// SAFETY: every call is forwarded to the system allocator unchanged.
+#[cfg(feature = "bench")]
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { std::alloc::System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        unsafe { std::alloc::System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { std::alloc::System.realloc(ptr, layout, new_size) }
+    }
+}
+
+/// The allocations so far, `None` without the bench feature.
+fn allocation_count() -> Option<u64> {
+    #[cfg(feature = "bench")]
+    return Some(ALLOCATIONS.load(Ordering::Relaxed));
+    #[cfg(not(feature = "bench"))]
+    None
+}
+
+/// A plausible value of `field` for GPU `index` of the synthetic node.
+fn bench_value(field: &str, index: usize) -> String {
+    match field {
+        "index" => index.to_string(),
+        "uuid" => format!("GPU-{:08x}-0000-0000-0000-000000000000", index),
+        "utilization.gpu" => "37".to_string(),
+        "memory.free" => "61440".to_string(),
+        "memory.total" => "81920".to_string(),
+        "persistence_mode" => "Enabled".to_string(),
+        "ecc.errors.uncorrected.aggregate.total" => "0".to_string(),
+        "temperature.gpu" => "45".to_string(),
+        "memory.used" => "20480".to_string(),
+        "power.limit" => "400.00".to_string(),
+        "power.draw" => "123.45".to_string(),
+        _ => "[N/A]".to_string(),
+    }
+}
+
+/// Parse the output of the GPU query, with all of [`QUERY_FIELDS`] and the
+/// fields of `filters`, for a synthetic node of `gpus` GPUs.
+fn bench_parsing(gpus: usize, iterations: u32, filters: &[FieldFilter]) {
+    let output: String = (0..gpus)
+        .map(|i| {
+            let mut values: Vec<String> = QUERY_FIELDS.iter().map(|x| bench_value(x, i)).collect();
+            values.extend(filters.iter().map(|x| bench_value(&x.field, i)));
+            values.join(", ") + "\n"
+        })
+        .collect();
+    let iterations = iterations.max(1);
+    let measure = |name: &str, parse: &mut dyn FnMut()| {
+        let allocations = allocation_count();
+        let started = Instant::now();
+        for _ in 0..iterations {
+            parse();
+        }
+        let elapsed = started.elapsed();
+        let per_parse = elapsed.as_secs_f64() * 1e6 / iterations as f64;
+        match allocations.zip(allocation_count()) {
+            Some((before, after)) => println!(
+                "{:<8} {:>10.2} us/parse {:>8.1} allocations/parse",
+                name,
+                per_parse,
+                (after - before) as f64 / iterations as f64
+            ),
+            None => println!("{:<8} {:>10.2} us/parse", name, per_parse),
+        }
+    };
+    println!(
+        "Parsing the output of {} GPU(s) with {} field(s), {} times",
+        gpus,
+        QUERY_FIELDS.len() + filters.len(),
+        iterations
+    );
+    measure("fresh", &mut || {
+        let gpu_info_list = parse_cuda_output(&output, &QUERY_FIELDS, filters.len()).unwrap();
+        std::hint::black_box(gpu_info_list);
+    });
+    let mut gpu_info_list = vec![];
+    measure("reused", &mut || {
+        parse_cuda_output_into(&output, &QUERY_FIELDS, filters.len(), &mut gpu_info_list).unwrap();
+        std::hint::black_box(&gpu_info_list);
+    });
+    if allocation_count().is_none() {
+        println!("Build with --features bench to count the allocations");
+    }
+}
+
+/// Measure one reading of the local GPUs through nvidia-smi, as each check does
+/// by default, and through NVML.
+fn bench_queries(config: &Config, iterations: u32) {
+    let iterations = iterations.max(1);
+    let measure = |name: &str, read: &mut dyn FnMut() -> Result<(), String>| {
+        let started = Instant::now();
+        for _ in 0..iterations {
+            if let Err(e) = read() {
+                println!("{:<10} failed: {}", name, e);
+                return;
+            }
+        }
+        println!(
+            "{:<10} {:>10.3} ms/check",
+            name,
+            started.elapsed().as_secs_f64() * 1e3 / iterations as f64
+        );
+    };
+    println!("Reading the local GPUs, {} times", iterations);
+    let mut gpu_info_list = vec![];
+    measure("nvidia-smi", &mut || {
+        query_gpus(
+            Backend::Nvidia,
+            &Node::Local,
+            &config.nvidia_smi_extra_args,
+            &config.filter_fields,
+        )
+        .and_then(|x| parse_cuda_output_into(&x.output, &x.fields, x.filters, &mut gpu_info_list))
+        .map_err(|e| e.to_string())
+    });
+    #[cfg(feature = "nvml")]
+    match nvml::shared() {
+        Ok(nvml) => measure("nvml", &mut || read_nvml_into(nvml, &mut gpu_info_list)),
+        Err(e) => println!("{:<10} failed: {}", "nvml", e),
+    }
+    #[cfg(not(feature = "nvml"))]
+    println!("{:<10} not built, enable the nvml feature", "nvml");
+}
+
+/// Check and report the local GPU setup, returns whether it's healthy.
+/// The driver and CUDA checks are for NVIDIA, with the amd backend only the
+/// GPU query is checked.
+fn doctor(config: &Config, json_output: bool) -> bool {
+    let mut problems = vec![];
+    let nvidia = config.backend == Backend::Nvidia;
+    let driver_version = match nvidia.then(query_driver_version) {
+        None => None,
+        Some(Ok(v)) => Some(v),
+        Some(Err(e)) => {
+            problems.push(format!("driver version: {}", e));
+            None
+        }
+    };
+    if let (Some(version), Some(min_version)) = (&driver_version, &config.min_driver_version) {
+        match min_version.parse::<DriverVersion>() {
+            Ok(v) if *version < v => problems.push(format!(
+                "NVIDIA driver {} is older than the required {}",
+                version, v
+            )),
+            Ok(_) => {}
+            Err(e) => problems.push(format!("min_driver_version: {}", e)),
+        }
+    }
+    let cuda_version = match nvidia.then(query_cuda_version) {
+        None => None,
+        Some(Ok(v)) => Some(v),
+        Some(Err(e)) => {
+            problems.push(format!("CUDA version: {}", e));
+            None
+        }
+    };
+    let mut gpu_info_list = vec![];
+    let gpu_count = match query_gpus(
+        config.backend,
+        &Node::Local,
+        &config.nvidia_smi_extra_args,
+        &config.filter_fields,
+    )
+    .and_then(|x| {
+        config
+            .backend
+            .gpu_backend()
+            .parse_into(&x, &mut gpu_info_list)
+    })
+    .map(|_| gpu_info_list)
+    {
+        Ok(v) if v.is_empty() => {
+            problems.push("no GPU found".to_string());
+            Some(0)
+        }
+        Ok(v) => Some(v.len()),
+        Err(e) => {
+            problems.push(format!("GPU query: {}", e));
+            None
+        }
+    };
+    let healthy = problems.is_empty();
+
+    if json_output {
+        println!(
+            "{}",
+            serde_json::json!({
+                "healthy": healthy,
+                "driver_version": driver_version.map(|x| x.to_string()),
+                "gpu_count": gpu_count,
+                "cuda_version": cuda_version,
+                "problems": problems,
+            })
+        );
+        return healthy;
+    }
+    let unknown = || "unknown".to_string();
+    println!(
+        "{:<16} {}",
+        "driver version",
+        driver_version.map_or_else(unknown, |x| x.to_string())
+    );
+    println!(
+        "{:<16} {}",
+        "CUDA version",
+        cuda_version.unwrap_or_else(unknown)
+    );
+    println!(
+        "{:<16} {}",
+        "GPUs",
+        gpu_count.map_or_else(unknown, |x| x.to_string())
+    );
+    println!("{:<16} {}", "healthy", if healthy { "yes" } else { "no" });
+    for x in &problems {
+        println!("  - {}", x);
+    }
+    healthy
+}
+
+fn check_driver_version(min_version: &str) {
+    let min_version = match min_version.parse::<DriverVersion>() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("min_driver_version: {}", e);
+            exit(1);
+        }
+    };
+    let version = query_driver_version().unwrap_or_else(|e| exit_nvidia_smi_error(&e));
+    info!("NVIDIA driver version: {}", version);
+    if version < min_version {
+        error!(
+            "NVIDIA driver {} is older than the required {}",
+            version, min_version
+        );
+        exit(1);
+    }
+}
+
+/// A reason for the resource not being enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Criterion {
+    /// Fewer GPUs are present than requested.
+    Count,
+    Memory,
+    /// The free memory relative to the total memory.
+    MemoryPercent,
+    Utilization,
+    SelfStacking,
+    /// The GPU runs a compute process while `require_exclusive` is set.
+    Exclusive,
+    /// Another instance holds the lock file of the GPU.
+    Reserved,
+    /// Persistence mode is off while `persistence_mode` is `require`.
+    PersistenceMode,
+    /// Uncorrected ECC errors while `skip_ecc_errors` is set.
+    EccErrors,
+    /// The temperature rose more than `max_temp_delta`.
+    TemperatureRise,
+    /// The soak command failed on the GPU less than `soak_cooldown` ago.
+    SoakFailed,
+    /// Enough GPUs are available, but too few behind distinct PCIe root complexes.
+    PcieSpread,
+    /// A field of `filter_fields` doesn't compare as required.
+    FilterField,
+}
+
+impl std::fmt::Display for Criterion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Criterion::Count => "count",
+            Criterion::Memory => "memory",
+            Criterion::MemoryPercent => "memory-percent",
+            Criterion::Utilization => "utilization",
+            Criterion::SelfStacking => "self-stacking",
+            Criterion::Exclusive => "exclusive",
+            Criterion::Reserved => "reserved",
+            Criterion::PersistenceMode => "persistence-mode",
+            Criterion::EccErrors => "ecc-errors",
+            Criterion::TemperatureRise => "temperature-rise",
+            Criterion::SoakFailed => "soak-failed",
+            Criterion::PcieSpread => "pcie-spread",
+            Criterion::FilterField => "filter-field",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The criteria this GPU fails, empty if it is available.
+fn failed_criteria(gpu_info: &GPUInfo, config: &Config) -> Vec<Criterion> {
+    let mut failed = vec![];
+    if gpu_info.memory_free < memory_required_mib(gpu_info, config) {
+        failed.push(Criterion::Memory);
+    }
+    if config.min_free_memory_percent > 0
+        && (gpu_info.memory_free as usize) * 100
+            < config.min_free_memory_percent * usable_memory_mib(gpu_info, config) as usize
+    {
+        failed.push(Criterion::MemoryPercent);
+    }
+    if gpu_info.gpu_free < config.gpu_percent {
+        failed.push(Criterion::Utilization);
+    }
+    if !config.allow_self_stacking && is_self_stacked(gpu_info) {
+        failed.push(Criterion::SelfStacking);
+    }
+    if config.require_exclusive && !gpu_info.processes.is_empty() {
+        info!(
+            "GPU {} runs {} compute process(es)",
+            gpu_info.index,
+            gpu_info.processes.len()
+        );
+        failed.push(Criterion::Exclusive);
+    }
+    if let Some(dir) = &config.reserve_dir
+        && is_reserved(dir, gpu_info.index)
+    {
+        failed.push(Criterion::Reserved);
+    }
+    if config.persistence_mode == PersistenceMode::Require && !gpu_info.persistence_mode {
+        failed.push(Criterion::PersistenceMode);
+    }
+    if config.skip_ecc_errors
+        && let Some(n) = gpu_info.ecc_errors
+        && n > 0
+    {
+        info!(
+            "GPU {} reports {} uncorrected ECC error(s)",
+            gpu_info.index, n
+        );
+        failed.push(Criterion::EccErrors);
+    }
+    if let (Some(max), Some(rise)) = (config.max_temp_delta, gpu_info.temperature_rise)
+        && rise > max
+    {
+        failed.push(Criterion::TemperatureRise);
+    }
+    if config
+        .soak_failed
+        .iter()
+        .any(|x| x.1 == gpu_info.index && x.2 > Instant::now())
+    {
+        failed.push(Criterion::SoakFailed);
+    }
+    let unmatched = config
+        .filter_fields
+        .iter()
+        .enumerate()
+        .find(|(i, x)| !gpu_info.field_values.get(*i).is_some_and(|v| x.matches(v)));
+    if let Some((i, filter)) = unmatched {
+        info!(
+            "GPU {} fails {}, it is {}",
+            gpu_info.index,
+            filter,
+            gpu_info
+                .field_values
+                .get(i)
+                .map_or("missing", |x| x.as_str())
+        );
+        failed.push(Criterion::FilterField);
+    }
+    failed
+}
+
+/// A comparison of a field of the nvidia-smi GPU query, i.e. `fan.speed < 80`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+struct FieldFilter {
+    field: String,
+    op: CompareOp,
+    value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareOp {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+}
+
+impl CompareOp {
+    const ALL: [(&str, CompareOp); 5] = [
+        ("<=", CompareOp::LessEqual),
+        (">=", CompareOp::GreaterEqual),
+        ("==", CompareOp::Equal),
+        ("<", CompareOp::Less),
+        (">", CompareOp::Greater),
+    ];
+}
+
+impl std::fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = CompareOp::ALL.iter().find(|x| x.1 == *self).unwrap().0;
+        write!(f, "{}", name)
+    }
+}
+
+impl FieldFilter {
+    /// Whether the queried `value` passes, compared as numbers if the filter's
+    /// value is one, where a value that isn't (i.e. `[N/A]`) fails, and as
+    /// strings otherwise.
+    fn matches(&self, value: &str) -> bool {
+        let value = value.trim();
+        let Ok(threshold) = self.value.parse::<f64>() else {
+            return value == self.value;
+        };
+        let Ok(value) = value.parse::<f64>() else {
+            return false;
+        };
+        match self.op {
+            CompareOp::Less => value < threshold,
+            CompareOp::LessEqual => value <= threshold,
+            CompareOp::Greater => value > threshold,
+            CompareOp::GreaterEqual => value >= threshold,
+            CompareOp::Equal => value == threshold,
+        }
+    }
+}
+
+impl FromStr for FieldFilter {
+    type Err = String;
+
+    /// Parse `<FIELD> <OP> <VALUE>`, the spaces being optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || {
+            format!(
+                "expected <FIELD> <OP> <VALUE>, i.e. 'fan.speed < 80': {}",
+                s
+            )
+        };
+        let start = s.find(['<', '>', '=']).ok_or_else(usage)?;
+        let (name, op) = CompareOp::ALL
+            .iter()
+            .find(|x| s[start..].starts_with(x.0))
+            .ok_or_else(usage)?;
+        let field = s[..start].trim();
+        let value = s[start + name.len()..].trim();
+        if field.is_empty() || value.is_empty() {
+            return Err(usage());
+        }
+        // it is joined into the query, a comma would shift the other fields
+        if !field
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '.' || x == '_')
+        {
+            return Err(format!("invalid query field: {}", field));
+        }
+        if *op != CompareOp::Equal && value.parse::<f64>().is_err() {
+            return Err(format!("{} only compares numbers, not {}", name, value));
+        }
+        Ok(FieldFilter {
+            field: field.to_string(),
+            op: *op,
+            value: value.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for FieldFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.field, self.op, self.value)
+    }
+}
+
+impl TryFrom<String> for FieldFilter {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<FieldFilter> for String {
+    fn from(filter: FieldFilter) -> String {
+        filter.to_string()
+    }
+}
+
+fn is_gpu_available(gpu_info: &GPUInfo, config: &Config) -> bool {
+    failed_criteria(gpu_info, config).is_empty()
+}
+
+/// Whether the current user already has a process on this GPU.
+fn is_self_stacked(gpu_info: &GPUInfo) -> bool {
+    let user = current_user();
+    gpu_info.processes.iter().any(|x| {
+        let mine = x.user.as_deref() == Some(user);
+        if mine {
+            info!(
+                "GPU {} already runs process {} of {}",
+                gpu_info.index, x.pid, user
+            );
+        }
+        mine
+    })
+}
+
+/// The available GPUs, best first according to `config.strategy`.
+/// `idle_since` records when each GPU became available, see [`wait_for_resource`].
+fn rank_gpus<'a>(
+    gpu_info_list: &'a [GPUInfo],
+    config: &Config,
+    idle_since: &HashMap<usize, Instant>,
+) -> Vec<&'a GPUInfo> {
+    let mut available_gpu: Vec<&GPUInfo> = gpu_info_list
+        .iter()
+        .filter(|x| is_gpu_available(x, config))
+        .collect();
+    match config.strategy {
+        Strategy::LeastUtil => {
+            available_gpu.sort_by_key(|x| (100 - x.gpu_free, std::cmp::Reverse(x.memory_free)))
+        }
+        Strategy::MostFreeMemory => {
+            available_gpu.sort_by_key(|x| (std::cmp::Reverse(x.memory_free), 100 - x.gpu_free))
+        }
+        Strategy::LongestIdle => available_gpu.sort_by_key(|x| {
+            let since = idle_since
+                .get(&x.index)
+                .copied()
+                .unwrap_or_else(Instant::now);
+            (since, 100 - x.gpu_free)
+        }),
+        Strategy::LeastRecentlyUsed => {
+            available_gpu.sort_by_key(|x| (x.last_used.unwrap_or(0), 100 - x.gpu_free))
+        }
+    }
+    if config.persistence_mode == PersistenceMode::Prefer {
+        // stable, so the strategy still orders each group
+        available_gpu.sort_by_key(|x| !x.persistence_mode);
+    }
+    available_gpu
+}
+
+/// One line per strategy with its aliases and its doc comment, the default
+/// marked.
+fn list_strategies() -> String {
+    let rows: Vec<(String, String)> = Strategy::value_variants()
+        .iter()
+        .filter_map(|strategy| {
+            let value = strategy.to_possible_value()?;
+            let mut name = value.get_name_and_aliases().collect::<Vec<_>>().join(", ");
+            if *strategy == Strategy::default() {
+                name.push_str(" (default)");
+            }
+            let help = value.get_help().map(|x| x.to_string()).unwrap_or_default();
+            Some((name, help))
+        })
+        .collect();
+    let width = rows.iter().map(|x| x.0.len()).max().unwrap_or(0);
+    rows.iter()
+        .map(|(name, help)| format!("{:<width$}  {}\n", name, help))
+        .collect()
+}
+
+/// Describe in prose what [`failed_criteria`], [`rank_gpus`] and
+/// [`check_resource_enough`] do for `strategy`, with the values of `config`.
+/// Keep it next to them when changing the selection.
+fn explain_strategy(strategy: Strategy, config: &Config) -> String {
+    let mut out = format!(
+        "Strategy {}\n\nFilter: a GPU is available when\n",
+        strategy.to_possible_value().unwrap().get_name()
+    );
+    match config.memory_percent {
+        Some(v) => out.push_str(&format!(
+            "  - at least {} % of its total memory is free (memory_percent)\n",
+            v
+        )),
+        None => out.push_str(&format!(
+            "  - it has at least {} G free memory (memory_per_core)\n",
+            config.memory_per_core
+        )),
+    }
+    if config.memory_metric == MemoryMetric::Computed {
+        out.push_str("    where free is total minus used memory (memory_metric)\n");
+    }
+    if config.min_free_memory_percent > 0 {
+        out.push_str(&format!(
+            "  - at least {} % of its memory is free (min_free_memory_percent)\n",
+            config.min_free_memory_percent
+        ));
+    }
+    if config.memory_overhead > 0.0
+        && (config.memory_percent.is_some() || config.min_free_memory_percent > 0)
+    {
+        out.push_str(&format!(
+            "    where the percentages are of the total memory less {} G (memory_overhead)\n",
+            config.memory_overhead
+        ));
+    }
+    out.push_str(&format!(
+        "  - at least {} % of it is unused (gpu_percent)\n",
+        config.gpu_percent
+    ));
+    if !config.allow_self_stacking {
+        out.push_str("  - it runs no process of the current user (allow_self_stacking)\n");
+    }
+    if config.require_exclusive {
+        out.push_str("  - it runs no compute process at all (require_exclusive)\n");
+    }
+    if config.reserve_dir.is_some() {
+        out.push_str("  - no other instance holds its lock file (reserve_dir)\n");
+    }
+    if config.persistence_mode == PersistenceMode::Require {
+        out.push_str("  - its persistence mode is on (persistence_mode)\n");
+    }
+    if config.skip_ecc_errors {
+        out.push_str("  - it reports no uncorrected ECC errors, or ECC is off (skip_ecc_errors)\n");
+    }
+    if let Some(v) = config.max_temp_delta {
+        out.push_str(&format!(
+            "  - its temperature rose at most {} C over the last {} checks, from the second check on (max_temp_delta, temp_delta_polls)\n",
+            v, config.temp_delta_polls
+        ));
+    }
+    if config.soak_command.is_some() {
+        out.push_str(&format!(
+            "  - the soak command didn't fail on it in the last {} s (soak_command, soak_cooldown)\n",
+            config.soak_cooldown
+        ));
+    }
+    for filter in &config.filter_fields {
+        out.push_str(&format!(
+            "  - its nvidia-smi field {} (filter_fields)\n",
+            filter
+        ));
+    }
+    if config.sample_count > 1 {
+        out.push_str(&format!(
+            "  where utilization and free memory are the average of {} readings {} ms apart (sample_count, sample_spacing)\n",
+            config.sample_count, config.sample_spacing
+        ));
+    }
+
+    out.push_str("\nRank: the available GPUs are ordered by\n");
+    if config.persistence_mode == PersistenceMode::Prefer {
+        out.push_str("  - persistence mode on before off (persistence_mode)\n");
+    }
+    match strategy {
+        Strategy::LeastUtil => {
+            out.push_str("  - the lowest utilization\n  - then the most free memory\n");
+        }
+        Strategy::MostFreeMemory => {
+            out.push_str("  - the most free memory\n  - then the lowest utilization\n");
+        }
+        Strategy::LongestIdle => {
+            out.push_str(
+                "  - the earliest check since which they have been available in a row\n  - then the lowest utilization\n",
+            );
+        }
+        Strategy::LeastRecentlyUsed => {
+            out.push_str(&format!(
+                "  - the oldest last use by this tool, never used first ({})\n  - then the lowest utilization\n",
+                match &config.usage_ledger {
+                    Some(v) => format!("usage_ledger {}", v.display()),
+                    None => "usage_ledger, by default next to the config file".to_string(),
+                }
+            ));
+        }
+    }
+    out.push_str("  ties keep the index order\n");
+    if !config.allow_gpus.is_empty() || !config.deny_gpus.is_empty() {
+        out.push_str("  GPUs outside allow_gpus or in deny_gpus are never considered\n");
+    }
+    if let Some(path) = &config.pending_reservation {
+        out.push_str(&format!(
+            "  GPUs listed in {} are left to the pending reservation\n",
+            path.display()
+        ));
+    }
+
+    if config.wait_for_indices.is_empty() {
+        out.push_str(&format!(
+            "\nSelect: the first {} ranked GPUs (core_count)",
+            config.core_count
+        ));
+    } else {
+        out.push_str(&format!(
+            "\nSelect: exactly the GPU(s) {} regardless of their rank (wait_for_indices)",
+            join_gpus(&config.wait_for_indices)
+        ));
+    }
+    if config.spread_pcie && config.wait_for_indices.is_empty() {
+        out.push_str(", at most one per PCIe root complex (spread_pcie)");
+    }
+    if let Some(v) = config.max_total_power
+        && config.wait_for_indices.is_empty()
+    {
+        out.push_str(&format!(
+            ", passing over the ones whose power limits would add up to more than {} W, even if fewer remain (max_total_power)",
+            v
+        ));
+    }
+    if config.selection_margin_percent > 0 {
+        out.push_str(&format!(
+            ", only if each exceeds the memory and utilization thresholds by {} % (selection_margin_percent)",
+            config.selection_margin_percent
+        ));
+    }
+    out.push_str(&format!(
+        ", once the same ones are selected on {} check(s) in a row {} s apart (check_times, check_interval).\n",
+        config.check_times, config.check_interval
+    ));
+    out
+}
+
+/// Pick `core_count` GPUs among the available ones, ranked by `config.strategy`,
+/// none if one of them passes the thresholds by less than `selection_margin_percent`.
+fn check_resource_enough(
+    gpu_info_list: &[GPUInfo],
+    config: &Config,
+    idle_since: &HashMap<usize, Instant>,
+) -> Option<Vec<usize>> {
+    let gpus = choose_gpus(gpu_info_list, config, idle_since)?;
+    if config.selection_margin_percent == 0 {
+        return Some(gpus);
+    }
+    // a second pass on the chosen set, one GPU on the edge rejects all of them
+    for index in &gpus {
+        let gpu_info = gpu_info_list.iter().find(|x| x.index == *index)?;
+        if !exceeds_margin(gpu_info, config, config.selection_margin_percent) {
+            info!(
+                "GPU {} passes the thresholds by less than {} %",
+                index, config.selection_margin_percent
+            );
+            return None;
+        }
+    }
+    Some(gpus)
+}
+
+/// Whether the free memory and the unused share of the GPU exceed their
+/// thresholds by `margin_percent`.
+fn exceeds_margin(gpu_info: &GPUInfo, config: &Config, margin_percent: usize) -> bool {
+    let with_margin = |threshold: u64| threshold * (100 + margin_percent as u64) / 100;
+    gpu_info.memory_free as u64 >= with_margin(memory_required_mib(gpu_info, config) as u64)
+        && gpu_info.gpu_free as u64 >= with_margin(config.gpu_percent as u64).min(100)
+}
+
+/// Ask on the terminal whether to run on the selected GPUs, if one of them
+/// passes the thresholds by less than `confirm_margin_percent` on a fresh
+/// check. Returns whether to run.
+fn confirm_tight_gpus(config: &Config, node: &Node, gpus: &[usize]) -> bool {
+    let gpu_info_list = match parse_cuda_info(config, node) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("GPU query failed, not checking the margin: {}", e);
+            return true;
+        }
+    };
+    let tight: Vec<&GPUInfo> = gpu_info_list
+        .iter()
+        .filter(|x| {
+            gpus.contains(&x.index) && !exceeds_margin(x, config, config.confirm_margin_percent)
+        })
+        .collect();
+    if tight.is_empty() {
+        return true;
+    }
+    for x in tight {
+        eprintln!(
+            "Selected GPU {} has only {:.1} GiB free (threshold {:.1} GiB) and {} % unused (threshold {} %).",
+            x.index,
+            x.memory_free as f64 / 1024.0,
+            memory_required_mib(x, config) as f64 / 1024.0,
+            x.gpu_free,
+            config.gpu_percent
+        );
+    }
+    eprint!("Proceed? [y/N] ");
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Whether `allow_gpus` and `deny_gpus` permit selecting the GPU.
+fn is_gpu_allowed(index: usize, config: &Config) -> bool {
+    (config.allow_gpus.is_empty() || config.allow_gpus.contains(&index))
+        && !config.deny_gpus.contains(&index)
+}
+
+/// The GPUs a pending larger job declared in `path`, separated by commas or
+/// whitespace, where `#` starts a comment. None without the file, which the job
+/// removes once it has its GPUs.
+fn read_pending_reservation(path: &Path) -> Vec<usize> {
+    let content = match fs::read_to_string(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return vec![],
+        Err(e) => {
+            warn!("Read pending reservation {} failed: {}", path.display(), e);
+            return vec![];
+        }
+    };
+    let mut gpus = vec![];
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap();
+        for x in line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|x| !x.is_empty())
+        {
+            match x.parse::<usize>() {
+                Ok(v) => gpus.push(v),
+                Err(e) => warn!(
+                    "Invalid GPU `{}` in pending reservation {}: {}",
+                    x,
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    }
+    gpus.sort_unstable();
+    gpus.dedup();
+    gpus
+}
+
+/// The GPUs selected among the available ones, before the margin check.
+fn choose_gpus(
+    gpu_info_list: &[GPUInfo],
+    config: &Config,
+    idle_since: &HashMap<usize, Instant>,
+) -> Option<Vec<usize>> {
+    let mut available_gpu = rank_gpus(gpu_info_list, config, idle_since);
+    available_gpu.retain(|x| is_gpu_allowed(x.index, config));
+    if let Some(path) = &config.pending_reservation {
+        let pending = read_pending_reservation(path);
+        if available_gpu.iter().any(|x| pending.contains(&x.index)) {
+            info!(
+                "Leaving GPU {} to the pending reservation in {}",
+                join_gpus(&pending),
+                path.display()
+            );
+            available_gpu.retain(|x| !pending.contains(&x.index));
+        }
+    }
+    if !config.wait_for_indices.is_empty() {
+        let all = config
+            .wait_for_indices
+            .iter()
+            .all(|i| available_gpu.iter().any(|x| x.index == *i));
+        return all.then(|| config.wait_for_indices.clone());
+    }
+    if config.spread_pcie {
+        // the best ranked GPU of each root complex
+        let mut roots = BTreeSet::new();
+        available_gpu.retain(|x| x.pcie_root.is_none_or(|root| roots.insert(root)));
+    }
+    if available_gpu.len() < config.core_count {
+        return None;
+    }
+    let gpus = take_within_power(&available_gpu, config);
+    (!gpus.is_empty()).then_some(gpus)
+}
+
+/// The first `core_count` of the ranked GPUs. With `max_total_power`, a GPU
+/// whose power limit doesn't fit the rest of the budget is passed over, so
+/// there may be fewer. A GPU without a limit counts with its draw, or with
+/// nothing if that's unknown as well.
+fn take_within_power(ranked: &[&GPUInfo], config: &Config) -> Vec<usize> {
+    let Some(budget) = config.max_total_power else {
+        return ranked
+            .iter()
+            .take(config.core_count)
+            .map(|x| x.index)
+            .collect();
+    };
+    let mut total = 0.0;
+    let mut gpus = vec![];
+    for x in ranked {
+        if gpus.len() == config.core_count {
+            break;
+        }
+        let power = x.power_limit.or(x.power_draw).unwrap_or(0.0);
+        if total + power <= budget as f64 {
+            total += power;
+            gpus.push(x.index);
+        }
+    }
+    if gpus.len() < config.core_count {
+        info!(
+            "max_total_power {} W only fits {} of {} GPU(s)",
+            budget,
+            gpus.len(),
+            config.core_count
+        );
+    }
+    gpus
+}
+
+/// The full evaluation of one check: every GPU with its metrics, the result of
+/// each criterion and its rank, along with the thresholds used.
+fn selection_report(
+    poll: usize,
+    gpu_info_list: &[GPUInfo],
+    config: &Config,
+    idle_since: &HashMap<usize, Instant>,
+    selected: Option<&Vec<usize>>,
+) -> Result<serde_json::Value, serde_json::Error> {
+    let ranked = rank_gpus(gpu_info_list, config, idle_since);
+    let mut criteria = vec![Criterion::Memory, Criterion::Utilization];
+    if config.min_free_memory_percent > 0 {
+        criteria.push(Criterion::MemoryPercent);
+    }
+    if !config.allow_self_stacking {
+        criteria.push(Criterion::SelfStacking);
+    }
+    if config.require_exclusive {
+        criteria.push(Criterion::Exclusive);
+    }
+    if config.reserve_dir.is_some() {
+        criteria.push(Criterion::Reserved);
+    }
+    if config.persistence_mode == PersistenceMode::Require {
+        criteria.push(Criterion::PersistenceMode);
+    }
+    if config.skip_ecc_errors {
+        criteria.push(Criterion::EccErrors);
+    }
+    if config.max_temp_delta.is_some() {
+        criteria.push(Criterion::TemperatureRise);
+    }
+    if config.soak_command.is_some() {
+        criteria.push(Criterion::SoakFailed);
+    }
+    if !config.filter_fields.is_empty() {
+        criteria.push(Criterion::FilterField);
+    }
+    let gpus = gpu_info_list
+        .iter()
+        .map(|x| {
+            // json! would panic on a serialization error, so convert explicitly
+            let gpu = serde_json::to_value(x)?;
+            let failed = failed_criteria(x, config);
+            let results: serde_json::Map<String, serde_json::Value> = criteria
+                .iter()
+                .map(|c| (c.to_string(), (!failed.contains(c)).into()))
+                .collect();
+            Ok(serde_json::json!({
+                "gpu": gpu,
+                "criteria": results,
+                "available": failed.is_empty(),
+                "rank": ranked.iter().position(|r| r.index == x.index),
+                "idle_ms": idle_since.get(&x.index).map(|t| t.elapsed().as_millis() as u64),
+            }))
+        })
+        .collect::<Result<Vec<serde_json::Value>, serde_json::Error>>()?;
+    Ok(serde_json::json!({
+        "poll": poll,
+        "thresholds": {
+            "core_count": config.core_count,
+            "memory_per_core": config.memory_per_core,
+            "memory_percent": config.memory_percent,
+            "memory_overhead": config.memory_overhead,
+            "gpu_percent": config.gpu_percent,
+            "min_free_memory_percent": config.min_free_memory_percent,
+            "selection_margin_percent": config.selection_margin_percent,
+            "strategy": config.strategy,
+            "persistence_mode": config.persistence_mode,
+            "allow_self_stacking": config.allow_self_stacking,
+            "require_exclusive": config.require_exclusive,
+            "spread_pcie": config.spread_pcie,
+            "skip_ecc_errors": config.skip_ecc_errors,
+            "max_total_power": config.max_total_power,
+            "max_temp_delta": config.max_temp_delta,
+            "filter_fields": config.filter_fields,
+        },
+        "gpus": gpus,
+        "selected": selected,
+    }))
+}
+
+/// The free memory this GPU needs in the MiB nvidia-smi reports: its share
+/// of `memory_percent`, or else `memory_per_core`, not to lose the memory
+/// below a whole GiB.
+fn memory_required_mib(gpu_info: &GPUInfo, config: &Config) -> u32 {
+    match config.memory_percent {
+        Some(v) => (usable_memory_mib(gpu_info, config) as u64 * v as u64 / 100) as u32,
+        None => config.memory_per_core.saturating_mul(1024),
+    }
+}
+
+/// The total memory of this GPU in MiB less `memory_overhead`, which the
+/// percentages of it are taken of.
+fn usable_memory_mib(gpu_info: &GPUInfo, config: &Config) -> u32 {
+    let overhead = (config.memory_overhead.max(0.0) * 1024.0) as u32;
+    gpu_info.memory_total.saturating_sub(overhead)
+}
+
+/// How far a GPU is from qualifying.
+#[derive(Serialize)]
+struct Shortfall {
+    index: usize,
+    /// In MiB.
+    memory: u32,
+    memory_percent: usize,
+    gpu: usize,
+    /// The largest shortfall relative to its threshold, i.e. 0.1 for 10% short.
+    #[serde(skip)]
+    ratio: f64,
+}
+
+impl std::fmt::Display for Shortfall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if self.memory > 0 {
+            parts.push(format!("{:.1} G memory", self.memory as f64 / 1024.0));
+        }
+        if self.memory_percent > 0 {
+            parts.push(format!("{} % free memory", self.memory_percent));
+        }
+        if self.gpu > 0 {
+            parts.push(format!("{} % GPU", self.gpu));
+        }
+        write!(f, "{} is {} short", self.index, parts.join(" and "))
+    }
+}
+
+/// Find the unavailable GPU closest to satisfying the thresholds. GPUs rejected
+/// for other reasons than the thresholds (i.e. reserved) are never close.
+fn closest_gpu(gpu_info_list: &[GPUInfo], config: &Config) -> Option<Shortfall> {
+    let relative = |short: f64, threshold: f64| {
+        if threshold > 0.0 {
+            short / threshold
+        } else {
+            0.0
+        }
+    };
+    gpu_info_list
+        .iter()
+        .filter(|x| {
+            let failed = failed_criteria(x, config);
+            !failed.is_empty()
+                && failed.iter().all(|c| {
+                    matches!(
+                        c,
+                        Criterion::Memory | Criterion::MemoryPercent | Criterion::Utilization
+                    )
+                })
+        })
+        .map(|x| {
+            let required = memory_required_mib(x, config);
+            let memory = required.saturating_sub(x.memory_free);
+            let usable = usable_memory_mib(x, config);
+            let free_percent = if usable > 0 {
+                x.memory_free as usize * 100 / usable as usize
+            } else {
+                0
+            };
+            let memory_percent = config.min_free_memory_percent.saturating_sub(free_percent);
+            let gpu = config.gpu_percent.saturating_sub(x.gpu_free);
+            let ratio = relative(memory as f64, required as f64)
+                .max(relative(
+                    memory_percent as f64,
+                    config.min_free_memory_percent as f64,
+                ))
+                .max(relative(gpu as f64, config.gpu_percent as f64));
+            Shortfall {
+                index: x.index,
+                memory,
+                memory_percent,
+                gpu,
+                ratio,
+            }
+        })
+        .min_by(|a, b| a.ratio.total_cmp(&b.ratio))
+}
+
+/// Whether to write the lifecycle events, see [`emit_event`].
+static EVENTS_NDJSON: AtomicBool = AtomicBool::new(false);
+
+/// Write `event` with `fields` (an object) as one JSON line to stderr, with
+/// `--events-ndjson`. Unlike the log, it is written at any verbosity.
+fn emit_event(event: &str, fields: serde_json::Value) {
+    if !EVENTS_NDJSON.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut line = serde_json::Map::new();
+    line.insert("event".into(), event.into());
+    line.insert("ts".into(), format_utc(unix_now()).into());
+    if let serde_json::Value::Object(fields) = fields {
+        line.extend(fields);
+    }
+    // a single write, so that the lines of several tasks don't interleave
+    let _ =
+        std::io::stderr().write_all(format!("{}\n", serde_json::Value::Object(line)).as_bytes());
+}
+
+/// Run the user's notification command, passing details through the environment.
+fn notify(cmd: &str, event: &str, index: usize, message: &str) {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("WIZARD_EVENT", event)
+        .env("WIZARD_GPU", index.to_string())
+        .env("WIZARD_MESSAGE", message)
+        .status();
+    match status {
+        Ok(v) if !v.success() => warn!("notify command exited with {}", v),
+        Ok(_) => {}
+        Err(e) => warn!("notify command failed: {}", e),
+    }
+}
+
+/// The command of `finally`, run once on whichever path the process exits.
+static FINALLY: OnceLock<String> = OnceLock::new();
+static FINALLY_DONE: AtomicBool = AtomicBool::new(false);
+/// Why the process exits, passed to the `finally` command. Paths that don't
+/// set it are errors.
+static EXIT_REASON: Mutex<&str> = Mutex::new("error");
+/// The pid of each running command (several with `--task`), and whether it
+/// leads its own process group, which gets the forwarded signals.
+static CHILDREN: Mutex<Vec<(u32, bool)>> = Mutex::new(vec![]);
+/// Whether a signal was received while the command ran.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn set_exit_reason(reason: &'static str) {
+    *EXIT_REASON.lock().unwrap_or_else(|e| e.into_inner()) = reason;
+}
+
+/// Run the `finally` command unless it already ran.
+fn run_finally() {
+    let Some(cmd) = FINALLY.get() else {
+        return;
+    };
+    if FINALLY_DONE.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let reason = *EXIT_REASON.lock().unwrap_or_else(|e| e.into_inner());
+    info!("Run finally command, exit reason: {}", reason);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("WIZARD_EXIT_REASON", reason)
+        .status();
+    match status {
+        Ok(v) if !v.success() => warn!("finally command exited with {}", v),
+        Ok(_) => {}
+        Err(e) => warn!("finally command failed: {}", e),
+    }
+}
+
+/// Arrange `cmd` to run on exit: `exit` and returning from `main` run it
+/// through `atexit`, a panic through the panic hook, and signals through
+/// [`install_signal_forwarding`], which exits instead of being killed.
+fn install_finally(cmd: &str) {
+    unsafe extern "C" {
+        fn atexit(callback: extern "C" fn()) -> i32;
+    }
+    extern "C" fn on_exit() {
+        run_finally();
+    }
+
+    if FINALLY.set(cmd.to_string()).is_err() {
+        return;
+    }
+    // SAFETY: the callback doesn't unwind out of atexit, run_finally doesn't panic
+    // on its failures.
+    if unsafe { atexit(on_exit) } != 0 {
+        warn!("Register the finally command failed, it won't run on exit");
+    }
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        hook(info);
+        set_exit_reason("panic");
+        run_finally();
+    }));
+}
+
+/// Handle SIGHUP, SIGINT and SIGTERM: while waiting, exit with 128 + the
+/// signal; while the command runs, forward the signal to it and exit with its
+/// status once it stopped, so that it isn't left running on the GPUs.
+fn install_signal_forwarding() {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+
+    // delivered through sigaction and a pipe, so the thread blocks until one
+    // arrives and acts on it outside the handler
+    let mut signals = match signal_hook::iterator::Signals::new([SIGHUP, SIGINT, SIGTERM]) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!(
+                "Install the signal handlers failed, signals aren't forwarded: {}",
+                e
+            );
+            return;
+        }
+    };
+    thread::spawn(move || {
+        for signum in signals.forever() {
+            let children = CHILDREN.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            if children.is_empty() {
+                warn!("Received signal {}, exit", signum);
+                set_exit_reason("signal");
+                exit(128 + signum);
+            }
+            // the command decides how to stop, the exit follows once it did
+            INTERRUPTED.store(true, Ordering::Relaxed);
+            for (pid, group) in children {
+                if group {
+                    info!("Forward signal {} to the command", signum);
+                    send_signal(-(pid as i32), signum);
+                } else if signum != SIGINT {
+                    // the command shares the terminal and our process group,
+                    // a Ctrl-C reached it already
+                    for x in process_tree(pid) {
+                        send_signal(x as i32, signum);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Whether stdin is a terminal with our process group in its foreground, so
+/// a command in another group couldn't read from it and wouldn't get job
+/// control (`Ctrl-Z`).
+fn in_terminal_foreground() -> bool {
+    unsafe extern "C" {
+        fn isatty(fd: i32) -> i32;
+        fn tcgetpgrp(fd: i32) -> i32;
+        fn getpgrp() -> i32;
+    }
+    // SAFETY: the calls only query the state of the process and fd 0.
+    unsafe { isatty(0) == 1 && tcgetpgrp(0) == getpgrp() }
+}
+
+/// The temperatures of each GPU over the last checks.
+#[derive(Default)]
+struct TemperatureHistory {
+    readings: HashMap<usize, std::collections::VecDeque<u32>>,
+}
+
+impl TemperatureHistory {
+    /// Add the temperatures of this check and fill `temperature_rise` of each
+    /// GPU with the rise over the last `polls` checks, from 2 readings on.
+    fn record(&mut self, gpu_info_list: &mut [GPUInfo], polls: usize) {
+        for gpu_info in gpu_info_list.iter_mut() {
+            let Some(temperature) = gpu_info.temperature else {
+                continue;
+            };
+            let readings = self.readings.entry(gpu_info.index).or_default();
+            // the rise over `polls` checks is measured from the reading before them
+            if readings.len() > polls.max(1) {
+                readings.pop_front();
+            }
+            readings.push_back(temperature);
+            if readings.len() >= 2 {
+                let rise = temperature.saturating_sub(readings[0]);
+                if rise > 0 {
+                    info!(
+                        "GPU {} temperature {} C, rose {} C over {} check(s)",
+                        gpu_info.index,
+                        temperature,
+                        rise,
+                        readings.len() - 1
+                    );
+                }
+                gpu_info.temperature_rise = Some(rise);
+            }
+        }
+    }
+}
+
+/// The available GPU count of the last checks, to extrapolate when it reaches
+/// `core_count`.
+#[derive(Default)]
+struct AvailabilityTrend {
+    history: std::collections::VecDeque<(Instant, usize)>,
+}
+
+impl AvailabilityTrend {
+    const LEN: usize = 10;
+    /// The estimate is printed every this many checks.
+    const REPORT_POLLS: usize = 5;
+
+    fn record(&mut self, available: usize) {
+        if self.history.len() == Self::LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back((Instant::now(), available));
+    }
+
+    /// The time until `core_count` GPUs are available if the count keeps its
+    /// least squares trend, `None` without enough history or an upward trend.
+    fn eta(&self, core_count: usize) -> Option<Duration> {
+        let (first, _) = *self.history.front()?;
+        let (_, available) = *self.history.back()?;
+        if self.history.len() < 3 || available >= core_count {
+            return None;
+        }
+        let points: Vec<(f64, f64)> = self
+            .history
+            .iter()
+            .map(|(t, n)| (t.duration_since(first).as_secs_f64(), *n as f64))
+            .collect();
+        let n = points.len() as f64;
+        let mean_t = points.iter().map(|x| x.0).sum::<f64>() / n;
+        let mean_n = points.iter().map(|x| x.1).sum::<f64>() / n;
+        let cov: f64 = points.iter().map(|x| (x.0 - mean_t) * (x.1 - mean_n)).sum();
+        let var: f64 = points.iter().map(|x| (x.0 - mean_t).powi(2)).sum();
+        if var <= 0.0 || cov <= 0.0 {
+            return None;
+        }
+        let slope = cov / var;
+        let seconds = (core_count - available) as f64 / slope;
+        Duration::try_from_secs_f64(seconds).ok()
+    }
+}
+
+/// How often each criterion kept GPUs from qualifying across a wait.
+#[derive(Default)]
+struct WaitStats {
+    failed_polls: usize,
+    blocked: BTreeMap<Criterion, usize>,
+}
+
+impl WaitStats {
+    fn record_failure(&mut self, gpu_info_list: &[GPUInfo], config: &Config) {
+        self.failed_polls += 1;
+        let mut criteria: BTreeSet<Criterion> = gpu_info_list
+            .iter()
+            .flat_map(|x| failed_criteria(x, config))
+            .collect();
+        if gpu_info_list.len() < config.core_count {
+            criteria.insert(Criterion::Count);
+        }
+        if config.spread_pcie
+            && gpu_info_list
+                .iter()
+                .filter(|x| is_gpu_available(x, config))
+                .count()
+                >= config.core_count
+        {
+            criteria.insert(Criterion::PcieSpread);
+        }
+        for c in criteria {
+            *self.blocked.entry(c).or_insert(0) += 1;
+        }
+    }
+
+    fn summary(&self, polls: usize, waited: Duration) -> String {
+        let mut blocked: Vec<(&Criterion, &usize)> = self.blocked.iter().collect();
+        blocked.sort_by_key(|x| std::cmp::Reverse(*x.1));
+        let parts: Vec<String> = blocked
+            .iter()
+            .map(|(c, n)| format!("{} on {}/{} checks", c, n, polls))
+            .collect();
+        format!(
+            "Resource wasn't enough on {}/{} checks over {:.0} s, blocked by {}",
+            self.failed_polls,
+            polls,
+            waited.as_secs_f64(),
+            parts.join(", ")
+        )
+    }
+}
+
+/// Check the GPUs once and print each allowed one with its readings and why
+/// it isn't available, if so. False if a node can't be queried.
+fn print_status(config: &Config) -> bool {
+    let nodes = if config.nodes.is_empty() {
+        vec![Node::Local]
+    } else {
+        config.nodes.iter().map(|x| Node::Ssh(x.clone())).collect()
+    };
+    let mut ok = true;
+    for node in &nodes {
+        if nodes.len() > 1 {
+            println!("{}:", node);
+        }
+        let mut gpu_info_list = match parse_cuda_info(config, node) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}: {}", node, e);
+                ok = false;
+                continue;
+            }
+        };
+        gpu_info_list.retain(|x| is_gpu_allowed(x.index, config));
+        // only attached when the selection needs them, but shown anyway
+        if config.allow_self_stacking && !config.require_exclusive {
+            config
+                .backend
+                .gpu_backend()
+                .attach_processes(node, &mut gpu_info_list);
+        }
+        println!(
+            "{:<4} {:>5} {:>15} {:>5} {:>11} {:>5} AVAILABLE",
+            "GPU", "UTIL", "MEMORY FREE", "TEMP", "POWER", "PROCS"
+        );
+        for gpu_info in &gpu_info_list {
+            let temperature = match gpu_info.temperature {
+                Some(v) => format!("{}C", v),
+                None => "-".to_string(),
+            };
+            let power = match (gpu_info.power_draw, gpu_info.power_limit) {
+                (Some(draw), Some(limit)) => format!("{:.0}/{:.0}W", draw, limit),
+                (Some(draw), None) => format!("{:.0}W", draw),
+                _ => "-".to_string(),
+            };
+            let failed = failed_criteria(gpu_info, config);
+            let available = if failed.is_empty() {
+                "yes".to_string()
+            } else {
+                let failed: Vec<String> = failed.iter().map(|x| x.to_string()).collect();
+                format!("no: {}", failed.join(", "))
+            };
+            println!(
+                "{:<4} {:>4}% {:>15} {:>5} {:>11} {:>5} {}",
+                gpu_info.index,
+                100 - gpu_info.gpu_free.min(100),
+                format!(
+                    "{:.1}/{:.1}G",
+                    gpu_info.memory_free as f64 / 1024.0,
+                    gpu_info.memory_total as f64 / 1024.0
+                ),
+                temperature,
+                power,
+                gpu_info.processes.len(),
+                available
+            );
+        }
+    }
+    ok
+}
+
+/// Check the GPUs once and print what would run where, without waiting or
+/// running anything. Returns whether a node has enough GPUs.
+fn dry_run(
+    config: &Config,
+    cmd: &str,
+    secret_envs: &[(String, String)],
+    format: OutputFormat,
+) -> bool {
+    let nodes = if config.nodes.is_empty() {
+        vec![Node::Local]
+    } else {
+        config.nodes.iter().map(|x| Node::Ssh(x.clone())).collect()
+    };
+    let mut checks = vec![];
+    for node in &nodes {
+        let mut check = serde_json::json!({"gpus": null});
+        if *node != Node::Local {
+            check["node"] = node.to_string().into();
+        }
+        let selection = match parse_cuda_info(config, node) {
+            Ok(mut gpu_info_list) => {
+                gpu_info_list.retain(|x| is_gpu_allowed(x.index, config));
+                let selection = check_resource_enough(&gpu_info_list, config, &HashMap::new());
+                if selection.is_none() {
+                    let available = gpu_info_list
+                        .iter()
+                        .filter(|x| is_gpu_available(x, config))
+                        .count();
+                    check["gap"] = serde_json::json!({
+                        "gpus": config.core_count.saturating_sub(available),
+                        "closest": closest_gpu(&gpu_info_list, config),
+                    });
+                }
+                selection
+            }
+            Err(e) => {
+                warn!("GPU query on {} failed: {}", node, e);
+                check["error"] = e.to_string().into();
+                None
+            }
+        };
+        let selection = selection.map(|mut v| {
+            if config.sort_output {
+                v.sort_unstable();
+            }
+            v
+        });
+        if format == OutputFormat::Text {
+            let gpus = match &selection {
+                Some(v) => format!("{}={}", config.gpu_env, join_gpus(v)),
+                None => "insufficient".to_string(),
+            };
+            if *node != Node::Local {
+                println!("{:<8} {}", "Node", node);
+            }
+            println!("{:<8} {}", "GPUs", gpus);
+        }
+        check["gpus"] = serde_json::json!(selection);
+        checks.push(check);
+    }
+    let sufficient = checks.iter().any(|x| !x["gpus"].is_null());
+    if format == OutputFormat::Json {
+        let secret_envs: Vec<String> = secret_envs
+            .iter()
+            .map(|(k, _)| format!("{}=<secret>", k))
+            .collect();
+        let mut verdict = serde_json::json!({
+            "sufficient": sufficient,
+            "gpu_env": config.gpu_env,
+            "command": cmd,
+            "set_envs": config.set_envs.iter().chain(&secret_envs).collect::<Vec<_>>(),
+            "unset_envs": config.unset_envs,
+            "env_passthrough": config.env_passthrough,
+        });
+        // like the run, the first node with enough GPUs is the one chosen
+        if let Some(check) = checks.iter().find(|x| !x["gpus"].is_null()) {
+            verdict["gpus"] = check["gpus"].clone();
+            if let Some(node) = check.get("node") {
+                verdict["node"] = node.clone();
+            }
+        } else {
+            verdict["gpus"] = serde_json::Value::Null;
+        }
+        if nodes == [Node::Local] {
+            let check = checks.pop().unwrap();
+            for key in ["gap", "error"] {
+                if let Some(v) = check.get(key) {
+                    verdict[key] = v.clone();
+                }
+            }
+        } else {
+            verdict["nodes"] = checks.into();
+        }
+        println!("{}", verdict);
+        return sufficient;
+    }
+    println!(
+        "{:<8} {}",
+        "Command",
+        if cmd.is_empty() { "(none)" } else { cmd }
+    );
+    for s in &config.set_envs {
+        println!("{:<8} {}", "Set", s);
+    }
+    for (k, _) in secret_envs {
+        println!("{:<8} {}=<secret>", "Set", k);
+    }
+    for s in &config.unset_envs {
+        println!("{:<8} {}", "Unset", s);
+    }
+    if !config.env_passthrough.is_empty() {
+        println!("{:<8} {}", "Keep", config.env_passthrough.join(" "));
+    }
+    // the text is for a human reading it, who decides from it
+    true
+}
+
+/// Run the soak command on the selected GPUs in the environment the command
+/// gets, returns whether it succeeded.
+fn soak_passed(
+    soak: &str,
+    node: &Node,
+    gpus: &[usize],
+    config: &Config,
+    secret_envs: &[(String, String)],
+) -> bool {
+    let gpus = join_gpus(gpus);
+    info!("Run soak command on GPU {} of {}", gpus, node);
+    let program = match node {
+        Node::Local => Program::Shell(soak.to_string()),
+        Node::Ssh(host) => {
+            Program::Shell(remote_command(host, soak, &gpus, config, &config.set_envs))
+        }
+    };
+    let mut command = program.command();
+    set_command_env(
+        &mut command,
+        &gpus,
+        &config.gpu_env,
+        &config.set_envs,
+        &config.unset_envs,
+        &config.env_passthrough,
+        secret_envs,
+    );
+    match command.status() {
+        Ok(v) if v.success() => true,
+        Ok(v) => {
+            warn!(
+                "Soak command failed on GPU {} of {} with {}, waiting again without them for {} s",
+                gpus, node, v, config.soak_cooldown
+            );
+            false
+        }
+        Err(e) => {
+            error!("Execute soak command failed: {}", e);
+            exit(1);
+        }
+    }
+}
+
+/// The outcome of waiting for the resource.
+struct Acquisition {
+    gpus: Vec<usize>,
+    /// The number of checks performed, 1 when the last selection was reused.
+    polls: usize,
+    waited: Duration,
+    reservation: Option<Reservation>,
+}
+
+impl Acquisition {
+    /// The selected GPUs as the comma separated list passed in `gpu_env`.
+    fn gpu_list(&self) -> String {
+        join_gpus(&self.gpus)
+    }
+}
+
+fn join_gpus(gpus: &[usize]) -> String {
+    gpus.iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Wait on the local GPUs, or on all `config.nodes` at once until the first one
+/// is ready.
+fn wait_on_nodes(config: &Config, print_selection: Option<SelectionJson>) -> (Node, Acquisition) {
+    let cancel = AtomicBool::new(false);
+    if let Some(socket) = &config.daemon_socket {
+        return (Node::Local, wait_on_daemon(socket, config, &cancel));
+    }
+    if config.nodes.is_empty() {
+        let acquisition = wait_for_resource(config, &Node::Local, print_selection, &cancel);
+        return (Node::Local, acquisition.unwrap());
+    }
+    let (tx, rx) = mpsc::channel();
+    let first = thread::scope(|s| {
+        for host in &config.nodes {
+            let mut config = config.clone();
+            // GPU indices repeat across nodes, so each gets its own locks
+            config.reserve_dir = config.reserve_dir.map(|x| x.join(host));
+            let node = Node::Ssh(host.clone());
+            config.soak_failed.retain(|x| x.0 == node);
+            let tx = tx.clone();
+            let cancel = &cancel;
+            s.spawn(move || {
+                if let Some(v) = wait_for_resource(&config, &node, print_selection, cancel) {
+                    let _ = tx.send((node, v));
+                }
+            });
+        }
+        // only the threads hold a sender, so a recv error means none got ready
+        drop(tx);
+        let first = rx.recv();
+        cancel.store(true, Ordering::Relaxed);
+        first
+    });
+    let Ok((node, acquisition)) = first else {
+        error!("No node became ready");
+        exit(1);
+    };
+    // nodes that got ready at the same time give their GPUs back
+    for (_, other) in rx.try_iter() {
+        if let Some(v) = other.reservation {
+            v.release();
+        }
+    }
+    info!("Node {} is ready", node);
+    (node, acquisition)
+}
+
+/// A selection request of a client to the daemon, one JSON line.
+#[derive(Serialize, Deserialize)]
+struct DaemonRequest {
+    /// The thresholds and strategy of the client.
+    config: Config,
+    /// The command line of the client, for the log.
+    command: String,
+    pid: u32,
+}
+
+/// The reply of the daemon, one JSON line.
+#[derive(Serialize, Deserialize)]
+struct DaemonReply {
+    /// `None` if the resource isn't enough.
+    gpus: Option<Vec<usize>>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// What the daemon knows about the GPUs.
+struct DaemonState {
+    gpu_info_list: Vec<GPUInfo>,
+    /// The GPUs handed out recently, not handed out again before their
+    /// command had the time to take them.
+    handed_out: HashMap<usize, Instant>,
+}
+
+impl DaemonState {
+    const HOLD: Duration = Duration::from_secs(30);
+}
+
+/// Poll the local GPUs every `check_interval` and answer the selection
+/// requests on `socket`, one at a time so that no two clients get the same GPUs.
+fn serve_daemon(socket: &Path, config: &Config) {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            error!("A daemon already listens on {}", socket.display());
+            exit(1);
+        }
+        let _ = fs::remove_file(socket);
+    }
+    let listener = match UnixListener::bind(socket) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Listen on {} failed: {}", socket.display(), e);
+            exit(1);
+        }
+    };
+    // the requests may rely on any of them
+    let mut poll_config = config.clone();
+    poll_config.allow_self_stacking = false;
+    poll_config.spread_pcie = true;
+    let state = Arc::new(Mutex::new(DaemonState {
+        gpu_info_list: parse_cuda_info(&poll_config, &Node::Local)
+            .unwrap_or_else(|e| exit_nvidia_smi_error(&e)),
+        handed_out: HashMap::new(),
+    }));
+    let poll_state = state.clone();
+    thread::spawn(move || {
+        loop {
+            sleep(Duration::from_secs(poll_config.check_interval));
+            let gpu_info_list = match parse_cuda_info(&poll_config, &Node::Local) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("GPU query failed, keep the last check: {}", e);
+                    continue;
+                }
+            };
+            let mut state = poll_state.lock().unwrap_or_else(|e| e.into_inner());
+            state.gpu_info_list = gpu_info_list;
+            state
+                .handed_out
+                .retain(|_, since| since.elapsed() < DaemonState::HOLD);
+        }
+    });
+    println!(
+        "*** Serving on {}, checking every {} s ***",
+        socket.display(),
+        config.check_interval
+    );
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Accept failed: {}", e);
+                continue;
+            }
+        };
+        // a stuck client mustn't block the others for long
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+        let mut line = String::new();
+        let reply = match BufReader::new(&stream).read_line(&mut line) {
+            Ok(_) => match serde_json::from_str::<DaemonRequest>(&line) {
+                Ok(request) => {
+                    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                    daemon_select(&mut state, &request)
+                }
+                Err(e) => DaemonReply {
+                    gpus: None,
+                    error: Some(format!("invalid request: {}", e)),
+                },
+            },
+            Err(e) => DaemonReply {
+                gpus: None,
+                error: Some(format!("read request failed: {}", e)),
+            },
+        };
+        let result = serde_json::to_string(&reply)
+            .map_err(std::io::Error::other)
+            .and_then(|x| writeln!(stream, "{}", x));
+        if let Err(e) = result {
+            warn!("Reply failed: {}", e);
+        }
+    }
+}
+
+/// Select for one request among the GPUs not handed out recently.
+fn daemon_select(state: &mut DaemonState, request: &DaemonRequest) -> DaemonReply {
+    let mut gpu_info_list: Vec<GPUInfo> = state
+        .gpu_info_list
+        .iter()
+        .filter(|x| !state.handed_out.contains_key(&x.index))
+        .cloned()
+        .collect();
+    if let Some(path) = &request.config.usage_ledger {
+        attach_last_use(path, &Node::Local, &mut gpu_info_list);
+    }
+    let gpus = check_resource_enough(&gpu_info_list, &request.config, &HashMap::new());
+    match &gpus {
+        Some(v) => {
+            info!(
+                "Hand out GPU {} to pid {} for `{}`",
+                join_gpus(v),
+                request.pid,
+                request.command
+            );
+            for &index in v {
+                state.handed_out.insert(index, Instant::now());
+            }
+        }
+        None => info!("Resource isn't enough for pid {}", request.pid),
+    }
+    DaemonReply { gpus, error: None }
+}
+
+/// Ask the daemon on `socket` every `check_interval` until it hands out enough
+/// GPUs. The daemon decides on its latest check, so `check_times` doesn't apply.
+fn wait_on_daemon(socket: &Path, config: &Config, cancel: &AtomicBool) -> Acquisition {
+    let started = Instant::now();
+    let mut polls = 0;
+    let request = DaemonRequest {
+        config: config.clone(),
+        command: env::args().skip(1).collect::<Vec<String>>().join(" "),
+        pid: std::process::id(),
+    };
+    let mut backoff = 0;
+    let mut paused = false;
+    loop {
+        check_drain(config);
+        if check_paused(config, &mut paused) {
+            sleep_unless_cancelled(next_check_interval(config, started, 0), cancel);
+            continue;
+        }
+        polls += 1;
+        let reply = match request_daemon(socket, &request) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Request the daemon on {} failed: {}", socket.display(), e);
+                exit(1);
+            }
+        };
+        if let Some(e) = reply.error {
+            error!("Daemon: {}", e);
+            exit(1);
+        }
+        match reply.gpus {
+            Some(gpus) => match acquire_reservation(config, &gpus) {
+                Ok(reservation) => {
+                    return Acquisition {
+                        gpus,
+                        polls,
+                        waited: started.elapsed(),
+                        reservation,
+                    };
+                }
+                Err(index) => {
+                    info!("GPU {} was reserved by another instance", index);
+                    backoff = 0;
+                }
+            },
+            None => {
+                info!(poll = polls; "Resource isn't enough.");
+                backoff += 1;
+            }
+        }
+        sleep_unless_cancelled(next_check_interval(config, started, backoff), cancel);
+    }
+}
+
+fn request_daemon(socket: &Path, request: &DaemonRequest) -> std::io::Result<DaemonReply> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let line = serde_json::to_string(request).map_err(std::io::Error::other)?;
+    writeln!(stream, "{}", line)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    serde_json::from_str(&reply).map_err(std::io::Error::other)
+}
+
+/// Sleep for `duration` unless `cancel` is set meanwhile, returns whether the
+/// wait should go on. SIGUSR1 ends the sleep early with
+/// `--poll-immediately-on-signal`.
+fn sleep_unless_cancelled(duration: Duration, cancel: &AtomicBool) -> bool {
+    let nudges = POLL_NUDGES.load(Ordering::Relaxed);
+    sleep_in_steps(Instant::now(), duration, || {
+        if POLL_NUDGES.load(Ordering::Relaxed) != nudges {
+            info!("Received SIGUSR1, check again now");
+            return true;
+        }
+        cancel.load(Ordering::Relaxed)
+    });
+    !cancel.load(Ordering::Relaxed)
+}
+
+/// Sleep until `duration` has passed since `started`, in short steps so that
+/// `stop` can end it early. The end is measured from `started` rather than by
+/// adding up the steps, so a step cut short by a signal, or one running long,
+/// doesn't shift it. Returns whether the whole duration passed.
+fn sleep_in_steps(started: Instant, duration: Duration, mut stop: impl FnMut() -> bool) -> bool {
+    loop {
+        if stop() {
+            return false;
+        }
+        let left = duration.saturating_sub(started.elapsed());
+        if left.is_zero() {
+            return true;
+        }
+        sleep(Duration::from_millis(50).min(left));
+    }
+}
+
+/// How many times SIGUSR1 was received, a change ends the sleeps between
+/// checks, of every node. A signal received during a check is ignored.
+static POLL_NUDGES: AtomicU64 = AtomicU64::new(0);
+
+fn install_poll_signal() {
+    // SAFETY: the action only updates an atomic, which is async-signal-safe.
+    let result = unsafe {
+        signal_hook::low_level::register(signal_hook::consts::SIGUSR1, || {
+            POLL_NUDGES.fetch_add(1, Ordering::Relaxed);
+        })
+    };
+    if let Err(e) = result {
+        warn!("Install the SIGUSR1 handler failed: {}", e);
+    }
+}
+
+/// The exit code when `max_wait` runs out, like EX_TEMPFAIL.
+const EXIT_TIMEOUT: i32 = 75;
+
+/// The sleep before the next check, shortened to end with `max_wait`. Exits
+/// once `max_wait` has passed since `started`, counting the checks as well.
+/// `backoff` is the number of checks in a row the resource wasn't enough.
+fn next_check_interval(config: &Config, started: Instant, backoff: u32) -> Duration {
+    let mut interval = Duration::from_secs(config.check_interval);
+    if config.backoff_max > config.check_interval {
+        let doubled = config.check_interval.saturating_mul(
+            1u64.checked_shl(backoff.saturating_sub(1))
+                .unwrap_or(u64::MAX),
+        );
+        interval = Duration::from_secs(doubled.min(config.backoff_max));
+    }
+    if config.jitter > 0.0 {
+        interval += Duration::from_secs_f64(config.jitter * random_fraction());
+    }
+    if config.max_wait == 0 {
+        return interval;
+    }
+    let max_wait = Duration::from_secs(config.max_wait);
+    let waited = started.elapsed();
+    if waited >= max_wait {
+        warn!(
+            "Resource isn't enough after waiting {:.0} s, give up (max_wait {} s)",
+            waited.as_secs_f64(),
+            config.max_wait
+        );
+        set_exit_reason("timeout");
+        exit(EXIT_TIMEOUT);
+    }
+    interval.min(max_wait - waited)
+}
+
+/// A random number in `0..1`, different on each call and in each process.
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The exit code when the drain file stops the wait, like EX_UNAVAILABLE.
+const EXIT_DRAINED: i32 = 69;
+
+/// Exit if the drain file exists, before any command starts.
+fn check_drain(config: &Config) {
+    if let Some(path) = &config.drain_file
+        && path.exists()
+    {
+        error!(
+            "{} exists, the node is being drained, not starting the command",
+            path.display()
+        );
+        set_exit_reason("drained");
+        exit(EXIT_DRAINED);
+    }
+}
+
+/// Whether the control file exists, which pauses the wait. `paused` is the
+/// state of the last call, only the transitions are logged.
+fn check_paused(config: &Config, paused: &mut bool) -> bool {
+    let Some(path) = &config.control_file else {
+        return false;
+    };
+    let exists = path.exists();
+    if exists != *paused {
+        let path = path.to_string_lossy();
+        if exists {
+            warn!("{} exists, paused", path);
+        } else {
+            warn!("{} was removed, resumed", path);
+        }
+        emit_event(
+            if exists { "paused" } else { "resumed" },
+            serde_json::json!({"control_file": path}),
+        );
+        *paused = exists;
+    }
+    exists
+}
+
+/// The checks in a row that selected the same GPUs. A set that was only
+/// momentarily free doesn't count, only the same GPUs available in a row do.
+#[derive(Default)]
+struct SelectionStreak {
+    count: usize,
+    last: Option<BTreeSet<usize>>,
+}
+
+impl SelectionStreak {
+    /// Count `selection` as one more check in a row if it selects the GPUs of
+    /// the last check, or start over. Returns the checks in a row.
+    fn record(&mut self, selection: Option<&[usize]>) -> usize {
+        let Some(gpus) = selection else {
+            self.count = 0;
+            self.last = None;
+            return 0;
+        };
+        let set: BTreeSet<usize> = gpus.iter().copied().collect();
+        if self.last.as_ref() != Some(&set) {
+            if self.count > 0 {
+                info!("Other GPUs are selected than on the last check, count again");
+            }
+            self.count = 0;
+        }
+        self.last = Some(set);
+        self.count += 1;
+        self.count
+    }
+}
+
+/// Poll `node` until the resource is enough, `None` if `cancel` is set before.
+fn wait_for_resource(
+    config: &Config,
+    node: &Node,
+    print_selection: Option<SelectionJson>,
+    cancel: &AtomicBool,
+) -> Option<Acquisition> {
+    let started = Instant::now();
+    let mut stats = WaitStats::default();
+    let mut streak = SelectionStreak::default();
+    let mut polls = 0;
+    let mut idle_since: HashMap<usize, Instant> = HashMap::new();
+    let mut near_miss = false;
+    let mut trend = AvailabilityTrend::default();
+    let mut temperatures = TemperatureHistory::default();
+    // the GPU count reported so far and for how many checks it stayed the same
+    let mut gpu_count = 0;
+    let mut stable_polls = 0;
+    let mut count_checked = false;
+    // the checks in a row the resource wasn't enough, for backoff_max
+    let mut backoff = 0;
+    // kept across checks, so that parsing reuses its allocations
+    let mut gpu_info_list = vec![];
+    let mut paused = false;
+    loop {
+        check_drain(config);
+        if check_paused(config, &mut paused) {
+            // the checks in a row start over after the pause
+            streak.count = 0;
+            if !sleep_unless_cancelled(next_check_interval(config, started, 0), cancel) {
+                return None;
+            }
+            continue;
+        }
+        polls += 1;
+        match parse_cuda_info_into(config, node, &mut gpu_info_list) {
+            Ok(()) => {}
+            // a local nvidia-smi that never worked won't start working, a
+            // later failure (i.e. a driver reload) or a node may recover
+            Err(e)
+                if *node == Node::Local
+                    && (polls == 1 || matches!(e, NvidiaSmiError::NotFound(_))) =>
+            {
+                exit_nvidia_smi_error(&e)
+            }
+            Err(e) => {
+                warn!("GPU query on {} failed, retry: {}", node, e);
+                if !sleep_unless_cancelled(next_check_interval(config, started, backoff), cancel) {
+                    return None;
+                }
+                continue;
+            }
+        };
+        // the other GPUs don't exist as far as the selection goes
+        let present = gpu_info_list.len();
+        gpu_info_list.retain(|x| is_gpu_allowed(x.index, config));
+        if !count_checked && present > 0 && gpu_info_list.is_empty() {
+            warn!(
+                "None of the {} GPU(s) present is allowed by allow_gpus and deny_gpus, the resource will never be enough.",
+                present
+            );
+        }
+        if gpu_info_list.len() == gpu_count {
+            stable_polls += 1;
+        } else {
+            gpu_count = gpu_info_list.len();
+            stable_polls = 1;
+        }
+        if config.count_stable_polls > 1
+            && (gpu_count == 0 || stable_polls < config.count_stable_polls)
+        {
+            info!(
+                "GPU count {} isn't stable yet: {}/{}",
+                gpu_count, stable_polls, config.count_stable_polls
+            );
+            if !sleep_unless_cancelled(next_check_interval(config, started, backoff), cancel) {
+                return None;
+            }
+            continue;
+        }
+        if !count_checked && gpu_info_list.len() < config.core_count {
+            let msg = format!(
+                "Only {} GPU(s) present, {} requested",
+                gpu_info_list.len(),
+                config.core_count
+            );
+            if config.strict_gpu_count {
+                error!("{}", msg);
+                exit(1);
+            }
+            warn!("{}, the resource will never be enough.", msg);
+        }
+        if !count_checked {
+            for i in &config.wait_for_indices {
+                if !gpu_info_list.iter().any(|x| x.index == *i) {
+                    warn!(
+                        "GPU {} is not present, the resource will never be enough.",
+                        i
+                    );
+                }
+            }
+        }
+        count_checked = true;
+        if config.max_temp_delta.is_some() {
+            temperatures.record(&mut gpu_info_list, config.temp_delta_polls);
+        }
+        let now = Instant::now();
+        let mut available = 0;
+        for gpu_info in &gpu_info_list {
+            if is_gpu_available(gpu_info, config) {
+                available += 1;
+                idle_since.entry(gpu_info.index).or_insert(now);
+            } else {
+                idle_since.remove(&gpu_info.index);
+            }
+        }
+        let selection = check_resource_enough(&gpu_info_list, config, &idle_since);
+        let cur_count = streak.record(selection.as_deref());
+        let done = selection.is_some() && cur_count >= config.check_times;
+        if print_selection == Some(SelectionJson::EveryCheck)
+            || (print_selection == Some(SelectionJson::Final) && done)
+        {
+            match selection_report(
+                polls,
+                &gpu_info_list,
+                config,
+                &idle_since,
+                selection.as_ref(),
+            ) {
+                Ok(report) => println!("{}", report),
+                Err(e) => warn!("Serialize selection report failed: {}", e),
+            }
+        }
+        match selection {
+            Some(gpus) => {
+                backoff = 0;
+                info!(poll = polls, available = available, enough = cur_count; "Resource is enough: {}", cur_count);
+                if cur_count >= config.check_times {
+                    match acquire_reservation(config, &gpus) {
+                        Ok(reservation) => {
+                            let waited = started.elapsed();
+                            if stats.failed_polls > 0 {
+                                eprintln!("*** {} ***", stats.summary(polls, waited));
+                            }
+                            return Some(Acquisition {
+                                gpus,
+                                polls,
+                                waited,
+                                reservation,
+                            });
+                        }
+                        Err(index) => {
+                            // another instance was faster, its lock excludes the GPU next time
+                            info!("GPU {} was reserved by another instance", index);
+                            streak.count = 0;
+                        }
+                    }
+                }
+            }
+            None => {
+                backoff += 1;
+                info!(poll = polls, available = available; "Resource isn't enough.");
+                stats.record_failure(&gpu_info_list, config);
+                trend.record(available);
+                if config.show_eta && stats.failed_polls % AvailabilityTrend::REPORT_POLLS == 0 {
+                    match trend.eta(config.core_count) {
+                        Some(v) => eprintln!(
+                            "*** ~estimated {} until {} GPUs free (best-effort, from the last {} checks) ***",
+                            if v.as_secs() < 120 {
+                                format!("{} s", v.as_secs())
+                            } else {
+                                format!("{} min", v.as_secs().div_ceil(60))
+                            },
+                            config.core_count,
+                            trend.history.len()
+                        ),
+                        None => eprintln!(
+                            "*** No trend towards {} free GPUs yet, no estimate ***",
+                            config.core_count
+                        ),
+                    }
+                }
+                match closest_gpu(&gpu_info_list, config) {
+                    Some(v) if v.ratio * 100.0 <= config.near_miss_percent as f64 => {
+                        // only report the transition, not every check
+                        if !near_miss {
+                            let msg = format!("Almost there, closest GPU {}", v);
+                            warn!(
+                                poll = polls,
+                                gpu = v.index,
+                                memory_short_mib = v.memory,
+                                gpu_short = v.gpu;
+                                "{}", msg
+                            );
+                            if let Some(cmd) = &config.notify_command {
+                                notify(cmd, "near_miss", v.index, &msg);
+                            }
+                        }
+                        near_miss = true;
+                    }
+                    Some(v) => {
+                        info!(
+                            poll = polls,
+                            gpu = v.index,
+                            memory_short_mib = v.memory,
+                            gpu_short = v.gpu;
+                            "Closest GPU {}", v
+                        );
+                        near_miss = false;
+                    }
+                    None => near_miss = false,
+                }
+            }
+        }
+        if !sleep_unless_cancelled(next_check_interval(config, started, backoff), cancel) {
+            return None;
+        }
+    }
+}
+
+fn arg_max() -> Option<usize> {
+    unsafe extern "C" {
+        fn sysconf(name: std::ffi::c_int) -> std::ffi::c_long;
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SC_ARG_MAX: std::ffi::c_int = 0;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SC_ARG_MAX: std::ffi::c_int = 1;
+    // SAFETY: sysconf has no preconditions, it returns -1 for unknown limits.
+    let v = unsafe { sysconf(SC_ARG_MAX) };
+    if v > 0 { Some(v as usize) } else { None }
+}
+
+/// Warn (or exit if `strict`) when the arguments of the command are likely to
+/// exceed the platform argument limits, since spawn would fail with an opaque
+/// `E2BIG`. `split` is whether the command could run without a shell instead.
+fn check_command_length(args: &[&str], split: bool, strict: bool) {
+    // Linux additionally limits every single argument to 32 pages.
+    #[cfg(target_os = "linux")]
+    const MAX_ARG_STRLEN: Option<usize> = Some(32 * 4096);
+    #[cfg(not(target_os = "linux"))]
+    const MAX_ARG_STRLEN: Option<usize> = None;
+
+    // the environment shares the ARG_MAX budget with the arguments
+    let env_len: usize = env::vars_os().map(|(k, v)| k.len() + v.len() + 2).sum();
+    let total = args.iter().map(|x| x.len() + 1).sum::<usize>() + env_len;
+    let longest = args.iter().map(|x| x.len()).max().unwrap_or(0);
+    let mut problem = None;
+    let mut hint = "a script file";
+    if let Some(limit) = MAX_ARG_STRLEN
+        && longest >= limit
+    {
+        problem = Some(format!(
+            "Command argument is {} bytes, exceeding the single argument limit of {} bytes",
+            longest, limit
+        ));
+        if split {
+            hint = "`--no-shell`";
+        }
+    } else if let Some(limit) = arg_max()
+        && total >= limit / 10 * 9
+    {
+        problem = Some(format!(
+            "Command and environment take {} bytes, approaching ARG_MAX of {} bytes",
+            total, limit
+        ));
+    }
+    if let Some(msg) = problem {
+        if strict {
+            error!("{}, use {} instead.", msg, hint);
+            exit(1);
+        }
+        warn!("{}, it may fail to start. Consider {}.", msg, hint);
+    }
+}
+
+/// Warn if the selection exceeds the limits of the tools reading `gpu_env`,
+/// which may fail on it in ways hard to trace back.
+fn check_gpu_env_limits(config: &Config, gpus: &[usize], value: &str) {
+    if let Some(max) = config.gpu_env_max_count
+        && gpus.len() > max
+    {
+        warn!(
+            "{} GPUs are set in {}, more than {} (gpu_env_max_count)",
+            gpus.len(),
+            config.gpu_env,
+            max
+        );
+    }
+    if let Some(max) = config.gpu_env_max_len
+        && value.len() > max
+    {
+        warn!(
+            "{}={} is {} characters long, more than {} (gpu_env_max_len)",
+            config.gpu_env,
+            value,
+            value.len(),
+            max
+        );
+    }
+}
+
+/// What [`run_command`] executes.
+enum Program {
+    /// A command line run by `sh -c`.
+    Shell(String),
+    /// A program and its literal arguments, run without a shell.
+    Exec(Vec<String>),
+}
+
+impl Program {
+    fn command(&self) -> Command {
+        match self {
+            Program::Shell(cmd) => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(cmd);
+                command
+            }
+            Program::Exec(argv) => {
+                let mut command = Command::new(&argv[0]);
+                command.args(&argv[1..]);
+                command
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Program::Shell(cmd) => write!(f, "{}", cmd),
+            Program::Exec(argv) => {
+                let words: Vec<String> = argv.iter().map(|x| shell_quote(x)).collect();
+                write!(f, "{}", words.join(" "))
+            }
+        }
+    }
+}
+
+/// Split the `NAME=value` entries of `set_envs` and expand the references to
+/// variables in their values, see [`expand_env_refs`]. A reference sees the
+/// entries before it, then the environment of this process.
+fn expand_set_envs(set_envs: &[String], strict: bool) -> Result<Vec<(String, String)>, String> {
+    let mut pairs: Vec<(String, String)> = vec![];
+    for s in set_envs {
+        let Some((k, v)) = s.split_once('=') else {
+            warn!("set_envs parse error: {}", s);
+            continue;
+        };
+        let lookup = |name: &str| match pairs.iter().rev().find(|x| x.0 == name) {
+            Some(x) => Some(x.1.clone()),
+            None => env::var(name).ok(),
+        };
+        let v = expand_env_refs(v, &lookup, strict).map_err(|e| format!("{} in `{}`", e, s))?;
+        pairs.push((k.to_string(), v));
+    }
+    Ok(pairs)
+}
+
+/// Expand `$VAR` and `${VAR}` in `value` with `lookup`, where `$$` is a `$`
+/// and a `$` not starting a name is kept. An undefined variable expands to
+/// nothing, or is an error if `strict`.
+fn expand_env_refs(
+    value: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    strict: bool,
+) -> Result<String, String> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, after) = if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => return Err("unclosed `${`".to_string()),
+            }
+        } else if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        } else {
+            let end = rest.find(|c: char| !is_name(c)).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(is_name)
+        {
+            if rest.starts_with('{') {
+                return Err(format!("invalid variable name `{}`", name));
+            }
+            out.push('$');
+            continue;
+        }
+        match lookup(name) {
+            Some(v) => out.push_str(&v),
+            None if strict => return Err(format!("`{}` is undefined", name)),
+            None => {}
+        }
+        rest = after;
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn run_command(
+    program: &Program,
+    gpus: &str,
+    gpu_env: &str,
+    env: &[String],
+    env_clear: &[String],
+    passthrough: &[String],
+    options: RunOptions,
+) -> i32 {
+    let cmd = program.to_string();
+    print_banner(options.start_banner, &cmd, gpus, options.banners_to_stderr);
+    let mut command = program.command();
+    set_command_env(
+        &mut command,
+        gpus,
+        gpu_env,
+        env,
+        env_clear,
+        passthrough,
+        &options.secret_envs,
+    );
+
+    if let Some(file) = options.cgroup {
+        // SAFETY: the closure only writes to an already open file, which is
+        // async-signal-safe and doesn't allocate.
+        unsafe {
+            command.pre_exec(move || (&file).write_all(b"0"));
+        }
+    }
+
+    // The command runs in its own group, so that a forwarded signal also
+    // reaches the processes the shell started. In the foreground of a terminal,
+    // the terminal signals the whole group already and the command keeps
+    // reading from it.
+    let group = !in_terminal_foreground();
+    if group {
+        command.process_group(0);
+    }
+
+    let mut child = match command.spawn() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Execute cmd failed: {}", e);
+            // like a shell for a command that can't be found or executed
+            return if e.kind() == std::io::ErrorKind::NotFound {
+                127
+            } else {
+                126
+            };
+        }
+    };
+    let pid = child.id();
+    CHILDREN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((pid, group));
+    let launched = Instant::now();
+    let gpu_indices: Vec<usize> = gpus.split(',').filter_map(|x| x.parse().ok()).collect();
+    emit_event(
+        "launched",
+        serde_json::json!({"pid": pid, "gpus": gpu_indices, "command": cmd}),
+    );
+    let profiler = options
+        .profile
+        .and_then(|(path, interval)| Profiler::start(path, interval, gpus, &options.node));
+    let yield_monitor = options
+        .yield_on
+        .map(|x| YieldMonitor::start(x, gpus, child.id()));
+    let warn_monitor = options.warn_on.map(|x| WarnMonitor::start(x, gpus));
+    let status = match child.wait() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Wait for the command (pid {}) failed: {}", pid, e);
+            exit(1);
+        }
+    };
+    CHILDREN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|x| x.0 != pid);
+    if let Some(v) = profiler {
+        v.stop();
+    }
+    if let Some(v) = yield_monitor {
+        v.stop();
+    }
+    if let Some(v) = warn_monitor {
+        v.stop();
+    }
+    print_banner(options.stop_banner, &cmd, gpus, options.banners_to_stderr);
+    emit_event(
+        "exited",
+        serde_json::json!({
+            "pid": pid,
+            "gpus": gpu_indices,
+            "code": status.code(),
+            "signal": status.signal(),
+            "duration_ms": launched.elapsed().as_millis() as u64,
+        }),
+    );
+    if let Some(code) = status.code() {
+        return code;
+    }
+    // a command killed by a signal exits like it would from a shell
+    let signum = status.signal().unwrap_or(0);
+    warn!(
+        "Command was killed by signal {} ({}){}",
+        signum,
+        signal_name(signum),
+        if status.core_dumped() {
+            ", core dumped"
+        } else {
+            ""
+        }
+    );
+    128 + signum
+}
+
+/// Run each of `tasks` by `sh -c` on its own `gpus.len() / tasks.len()` GPUs
+/// at once, and wait for all of them. The exit code is the one of the first
+/// task that failed, 0 if none did.
+fn run_tasks(
+    tasks: &[String],
+    config: &Config,
+    gpus: &[usize],
+    set_envs: &[String],
+    options: RunOptions,
+) -> i32 {
+    let per_task = gpus.len() / tasks.len();
+    let codes: Vec<i32> = thread::scope(|scope| {
+        let handles: Vec<_> = tasks
+            .iter()
+            .zip(gpus.chunks(per_task))
+            .map(|(task, task_gpus)| {
+                let cgroup = options.cgroup.as_ref().and_then(|x| match x.try_clone() {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        warn!("Open the cgroup for `{}` failed: {}", task, e);
+                        None
+                    }
+                });
+                let options = RunOptions {
+                    profile: None,
+                    yield_on: YieldOn::from_config(config, &options.node),
+                    warn_on: WarnOn::from_config(config, &options.node),
+                    node: options.node.clone(),
+                    cgroup,
+                    secret_envs: options.secret_envs.clone(),
+                    ..options
+                };
+                scope.spawn(move || {
+                    let gpus = join_gpus(task_gpus);
+                    let cmd = match &config.command_prefix {
+                        Some(prefix) if !prefix.trim().is_empty() => {
+                            format!("{} {}", prefix, task)
+                        }
+                        _ => task.clone(),
+                    };
+                    let program = match &options.node {
+                        Node::Local => Program::Shell(cmd),
+                        Node::Ssh(host) => {
+                            Program::Shell(remote_command(host, &cmd, &gpus, config, set_envs))
+                        }
+                    };
+                    run_command(
+                        &program,
+                        &gpus,
+                        &config.gpu_env,
+                        set_envs,
+                        &config.unset_envs,
+                        &config.env_passthrough,
+                        options,
+                    )
+                })
+            })
+            .collect();
+        handles.into_iter().map(|x| x.join().unwrap_or(1)).collect()
+    });
+    for ((task, task_gpus), code) in tasks.iter().zip(gpus.chunks(per_task)).zip(&codes) {
+        info!(
+            "Task `{}` on GPU {} exited with {}",
+            task,
+            join_gpus(task_gpus),
+            code
+        );
+    }
+    codes.into_iter().find(|x| *x != 0).unwrap_or(0)
+}
+
+/// Give `command` the environment of a command on `gpus`: with `passthrough`
+/// only the matching variables are kept, then `gpu_env`, `env` with its
+/// references expanded and the secrets are set, and `env_clear` removed.
+fn set_command_env(
+    command: &mut Command,
+    gpus: &str,
+    gpu_env: &str,
+    env: &[String],
+    env_clear: &[String],
+    passthrough: &[String],
+    secret_envs: &[(String, String)],
+) {
+    if !passthrough.is_empty() {
+        command.env_clear();
+        for (k, v) in env::vars_os() {
+            if let Some(name) = k.to_str()
+                && passthrough.iter().any(|x| glob_match(x, name))
+            {
+                command.env(&k, v);
+            }
+        }
+    }
+    command.env(gpu_env, gpus);
+
+    // bad references were rejected before waiting
+    let pairs = expand_set_envs(env, false).unwrap_or_else(|e| {
+        warn!("set_envs: {}", e);
+        vec![]
+    });
+    for (k, v) in pairs {
+        command.env(k, v);
+    }
+    for (k, v) in secret_envs {
+        command.env(k, v);
+    }
+    for s in env_clear {
+        command.env_remove(s);
+    }
+}
+
+/// The name of the common signals, i.e. SIGKILL from the OOM killer.
+fn signal_name(signum: i32) -> &'static str {
+    match signum {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        6 => "SIGABRT",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        _ => "unknown",
+    }
+}
+
+/// How to run the command besides its environment, and what to watch meanwhile.
+struct RunOptions<'a> {
+    profile: Option<(&'a Path, Duration)>,
+    yield_on: Option<YieldOn>,
+    warn_on: Option<WarnOn>,
+    /// Where the GPUs of the command are.
+    node: Node,
+    /// `cgroup.procs` of the cgroup the command joins before it starts.
+    cgroup: Option<fs::File>,
+    /// Set after `set_envs`, the values are never printed.
+    secret_envs: Vec<(String, String)>,
+    /// The banner templates, empty for none.
+    start_banner: &'a str,
+    stop_banner: &'a str,
+    /// Keep stdout for the command and the selection of `--output json`.
+    banners_to_stderr: bool,
+}
+
+const DEFAULT_START_BANNER: &str = "*** Start run `{cmd}` ***\n*** Using GPU `{gpus}` ***";
+const DEFAULT_STOP_BANNER: &str = "*** Stop run ***";
+
+/// Print a banner template unless it's empty.
+fn print_banner(template: &str, cmd: &str, gpus: &str, to_stderr: bool) {
+    if template.is_empty() {
+        return;
+    }
+    let banner = template
+        .replace("{cmd}", cmd)
+        .replace("{gpus}", gpus)
+        .replace("{gpu_count}", &gpus.split(',').count().to_string())
+        .replace("{timestamp}", &format_utc(unix_now()));
+    if to_stderr {
+        eprintln!("{}", banner);
+    } else {
+        println!("{}", banner);
+    }
+}
+
+/// `secs` since the epoch as an RFC 3339 UTC time, i.e. `2024-05-01T12:00:00Z`.
+fn format_utc(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // the civil date of a day count, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Open `cgroup.procs` of an existing cgroup for the command to join. Writing
+/// `0` moves the writing process, so the child can join it before exec and
+/// everything it starts stays confined.
+fn open_cgroup(path: &Path) -> Result<fs::File, String> {
+    if !cfg!(target_os = "linux") {
+        return Err("cgroups are only supported on Linux".to_string());
+    }
+    let procs = path.join("cgroup.procs");
+    if !path.is_dir() || !procs.exists() {
+        return Err(format!(
+            "cgroup {} doesn't exist or isn't a cgroup",
+            path.display()
+        ));
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&procs)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::PermissionDenied => format!(
+                "No permission to move processes into cgroup {}",
+                path.display()
+            ),
+            _ => format!("Open {} failed: {}", procs.display(), e),
+        })
+}
+
+/// Read the `KEY=VALUE` lines of a secret env file, skipping empty lines and
+/// `#` comments, the value may be quoted. Errors name the line, not its content.
+fn read_secret_envs(path: &Path) -> Result<Vec<(String, String)>, String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let name = path.display();
+    let metadata = fs::metadata(path).map_err(|e| format!("Read {} failed: {}", name, e))?;
+    if metadata.permissions().mode() & 0o004 != 0 {
+        warn!(
+            "Secret env file {} is readable by everyone, restrict it with chmod 600",
+            name
+        );
+    }
+    let content = fs::read_to_string(path).map_err(|e| format!("Read {} failed: {}", name, e))?;
+    let mut envs = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        match line.split_once('=') {
+            Some((k, v)) if is_env_name(k.trim()) => {
+                // like a shell, matching quotes around the value aren't part of it
+                let v = v.trim();
+                let v = ['"', '\'']
+                    .iter()
+                    .find_map(|q| v.strip_prefix(*q).and_then(|x| x.strip_suffix(*q)))
+                    .unwrap_or(v);
+                envs.push((k.trim().to_string(), v.to_string()))
+            }
+            _ => return Err(format!("{} line {}: expected KEY=VALUE", name, i + 1)),
+        }
+    }
+    info!(
+        "Read {} secret variable(s) from {}: {}",
+        envs.len(),
+        name,
+        envs.iter()
+            .map(|x| x.0.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    );
+    Ok(envs)
+}
+
+/// Samples utilization and memory of the selected GPUs into a csv file while the
+/// command runs.
+struct Profiler {
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Profiler {
+    const FIELDS: &str = "timestamp,index,utilization.gpu,memory.used,memory.free";
+
+    fn start(path: &Path, interval: Duration, gpus: &str, node: &Node) -> Option<Profiler> {
+        let mut file = match fs::File::create(path) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Create profile output {} failed: {}", path.display(), e);
+                return None;
+            }
+        };
+        if let Err(e) = writeln!(file, "{}", Self::FIELDS) {
+            warn!("Write profile output failed: {}", e);
+            return None;
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let id = format!("--id={}", gpus);
+        let node = node.clone();
+        let handle = thread::spawn(move || {
+            while !stop_flag.load(Ordering::Relaxed) {
+                let started = Instant::now();
+                match node
+                    .command("nvidia-smi")
+                    .args([
+                        "--query-gpu",
+                        Self::FIELDS,
+                        "--format",
+                        "csv,noheader,nounits",
+                        &id,
+                    ])
+                    .output()
+                {
+                    Ok(v) => {
+                        for line in String::from_utf8_lossy(&v.stdout).lines() {
+                            let row: Vec<&str> = line.split(',').map(|x| x.trim()).collect();
+                            if let Err(e) = writeln!(file, "{}", row.join(",")) {
+                                warn!("Write profile output failed: {}", e);
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Profile nvidia-smi failed: {}", e),
+                }
+                // sleep in short steps so that stopping doesn't wait a whole interval
+                sleep_in_steps(started, interval, || stop_flag.load(Ordering::Relaxed));
+            }
+        });
+        Some(Profiler { stop, handle })
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.handle.join().is_err() {
+            warn!("Profiler thread panicked");
+        }
+    }
+}
+
+/// Conditions for the command to give its GPUs back to others.
+struct YieldOn {
+    files: Vec<PathBuf>,
+    foreign_process: bool,
+    interval: Duration,
+    grace: Duration,
+    backend: Backend,
+    nvidia_smi_extra_args: Vec<String>,
+    node: Node,
+}
+
+impl YieldOn {
+    fn from_config(config: &Config, node: &Node) -> Option<YieldOn> {
+        let mut files: Vec<PathBuf> = config.yield_on_file.iter().cloned().collect();
+        if config.yield_on_drain {
+            files.extend(config.drain_file.iter().cloned());
+        }
+        if files.is_empty() && !config.yield_on_foreign_process {
+            return None;
+        }
+        Some(YieldOn {
+            files,
+            foreign_process: config.yield_on_foreign_process,
+            interval: Duration::from_secs(config.check_interval.max(1)),
+            grace: Duration::from_secs(config.yield_grace),
+            backend: config.backend,
+            nvidia_smi_extra_args: config.nvidia_smi_extra_args.clone(),
+            node: node.clone(),
+        })
+    }
+
+    /// Pids of the processes of other users on the given GPUs.
+    fn foreign_processes(&self, gpus: &[usize]) -> BTreeSet<u32> {
+        let backend = self.backend.gpu_backend();
+        let mut gpu_info_list = vec![];
+        if let Err(e) = query_gpus(self.backend, &self.node, &self.nvidia_smi_extra_args, &[])
+            .and_then(|x| backend.parse_into(&x, &mut gpu_info_list))
+        {
+            warn!("Query foreign processes failed: {}", e);
+            return BTreeSet::new();
+        }
+        backend.attach_processes(&self.node, &mut gpu_info_list);
+        let user = current_user();
+        gpu_info_list
+            .iter()
+            .filter(|x| gpus.contains(&x.index))
+            .flat_map(|x| &x.processes)
+            .filter(|x| x.user.as_deref() != Some(user))
+            .map(|x| x.pid)
+            .collect()
+    }
+}
+
+/// Terminates the command, first with SIGTERM and after a grace period with
+/// SIGKILL, once one of the [`YieldOn`] conditions is met.
+struct YieldMonitor {
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl YieldMonitor {
+    const SIGKILL: i32 = 9;
+    const SIGTERM: i32 = 15;
+
+    fn start(on: YieldOn, gpus: &str, pid: u32) -> YieldMonitor {
+        let gpus: Vec<usize> = gpus.split(',').filter_map(|x| x.parse().ok()).collect();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let handle = thread::spawn(move || {
+            // only processes showing up after the start are newer jobs
+            let known = if on.foreign_process {
+                on.foreign_processes(&gpus)
+            } else {
+                BTreeSet::new()
+            };
+            let wait = |duration: Duration| {
+                sleep_in_steps(Instant::now(), duration, || {
+                    stop_flag.load(Ordering::Relaxed)
+                })
+            };
+            while wait(on.interval) {
+                let reason = if let Some(file) = on.files.iter().find(|x| x.exists()) {
+                    format!("{} exists", file.display())
+                } else if on.foreign_process
+                    && let Some(pid) = on
+                        .foreign_processes(&gpus)
+                        .difference(&known)
+                        .next()
+                        .copied()
+                {
+                    format!("process {} of another user started", pid)
+                } else {
+                    continue;
+                };
+                warn!("Yielding the GPUs, {}", reason);
+                // the shell may not exec the command, so signal its whole tree,
+                // which may outlive the shell
+                let mut tree = process_tree(pid);
+                for x in &tree {
+                    send_signal(*x as i32, Self::SIGTERM);
+                }
+                let started = Instant::now();
+                while started.elapsed() < on.grace {
+                    tree.retain(|x| is_pid_alive(*x));
+                    if tree.is_empty() {
+                        return;
+                    }
+                    sleep(Duration::from_millis(50));
+                }
+                warn!("Command didn't exit within the grace period, killing it");
+                for x in &tree {
+                    send_signal(*x as i32, Self::SIGKILL);
+                }
+                return;
+            }
+        });
+        YieldMonitor { stop, handle }
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.handle.join().is_err() {
+            warn!("Yield monitor thread panicked");
+        }
+    }
+}
+
+/// Thresholds under which a selected GPU gets a warning, see [`WarnMonitor`].
+struct WarnOn {
+    /// In MiB.
+    memory_free: Option<u32>,
+    gpu_free: Option<usize>,
+    interval: Duration,
+    config: Config,
+    node: Node,
+}
+
+impl WarnOn {
+    fn from_config(config: &Config, node: &Node) -> Option<WarnOn> {
+        if config.warn_memory_free.is_none() && config.warn_gpu_free.is_none() {
+            return None;
+        }
+        Some(WarnOn {
+            memory_free: config.warn_memory_free.map(|x| (x * 1024.0) as u32),
+            gpu_free: config.warn_gpu_free,
+            interval: Duration::from_secs(config.check_interval.max(1)),
+            config: config.clone(),
+            node: node.clone(),
+        })
+    }
+}
+
+/// Logs a warning when a selected GPU drops under a [`WarnOn`] threshold, on
+/// start and every check interval while the command runs. A GPU is warned
+/// about again only after it was back over the threshold.
+struct WarnMonitor {
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl WarnMonitor {
+    fn start(on: WarnOn, gpus: &str) -> WarnMonitor {
+        let gpus: Vec<usize> = gpus.split(',').filter_map(|x| x.parse().ok()).collect();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut low_memory = BTreeSet::new();
+            let mut low_gpu = BTreeSet::new();
+            let mut gpu_info_list = vec![];
+            loop {
+                let started = Instant::now();
+                match read_gpus(&on.config, &on.node, &mut gpu_info_list) {
+                    Ok(()) => {
+                        for x in gpu_info_list.iter().filter(|x| gpus.contains(&x.index)) {
+                            if let Some(threshold) = on.memory_free {
+                                if x.memory_free >= threshold {
+                                    low_memory.remove(&x.index);
+                                } else if low_memory.insert(x.index) {
+                                    warn!(
+                                        "GPU {} free memory dropped to {:.1} GiB (warn threshold {:.1} GiB)",
+                                        x.index,
+                                        x.memory_free as f64 / 1024.0,
+                                        threshold as f64 / 1024.0
+                                    );
+                                }
+                            }
+                            if let Some(threshold) = on.gpu_free {
+                                if x.gpu_free >= threshold {
+                                    low_gpu.remove(&x.index);
+                                } else if low_gpu.insert(x.index) {
+                                    warn!(
+                                        "GPU {} unused share dropped to {} % (warn threshold {} %)",
+                                        x.index, x.gpu_free, threshold
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => info!("GPU query for the warn thresholds failed: {}", e),
+                }
+                // sleep in short steps so that stopping doesn't wait a whole interval
+                if !sleep_in_steps(started, on.interval, || stop_flag.load(Ordering::Relaxed)) {
+                    return;
+                }
+            }
+        });
+        WarnMonitor { stop, handle }
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.handle.join().is_err() {
+            warn!("Warn monitor thread panicked");
+        }
+    }
+}
+
+/// `pid` and all of its descendants.
+fn process_tree(pid: u32) -> Vec<u32> {
+    let output = match Command::new("ps").args(["-A", "-o", "pid=,ppid="]).output() {
+        Ok(v) => String::from_utf8_lossy(&v.stdout).to_string(),
+        Err(e) => {
+            warn!("Query process tree failed: {}", e);
+            return vec![pid];
+        }
+    };
+    let mut parents = vec![];
+    for line in output.lines() {
+        let mut field_it = line.split_whitespace();
+        if let (Some(Ok(pid)), Some(Ok(ppid))) = (
+            field_it.next().map(|x| x.parse::<u32>()),
+            field_it.next().map(|x| x.parse::<u32>()),
+        ) {
+            parents.push((pid, ppid));
+        }
+    }
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(parents.iter().filter(|x| x.1 == parent).map(|x| x.0));
+        i += 1;
+    }
+    tree
+}
+
+/// Send `signal` to `pid`, or to the process group `-pid` if it's negative.
+fn send_signal(pid: i32, signal: i32) {
+    unsafe extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    // SAFETY: kill has no memory preconditions, failures are reported by its result.
+    if unsafe { kill(pid, signal) } != 0 {
+        warn!(
+            "Send signal {} to {} failed: {}",
+            signal,
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Match `name` against a glob `pattern`, where `*` matches any sequence of
+/// characters and `?` matches a single one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut i, mut j) = (0, 0);
+    // position of the last `*` in the pattern and the name position it matched up to
+    let mut star = None;
+    while j < n.len() {
+        if i < p.len() && (p[i] == '?' || p[i] == n[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == '*' {
+            star = Some((i, j));
+            i += 1;
+        } else if let Some((si, sj)) = star {
+            i = si + 1;
+            j = sj + 1;
+            star = Some((si, sj + 1));
+        } else {
+            return false;
+        }
+    }
+    p[i..].iter().all(|x| *x == '*')
+}
+
+/// Quote `s` for POSIX shells, leaving it bare when it only has safe characters.
+fn shell_quote(s: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-+=,./:@%".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+fn is_env_name(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_env_name(s: &str) -> Result<String, String> {
+    if is_env_name(s) {
+        Ok(s.to_string())
+    } else {
+        Err(format!("not a variable name: {}", s))
+    }
+}
+
+/// Format the environment `run_command` would set as lines for `eval` in a shell.
+fn format_exports(gpus: &str, gpu_env: &str, env: &[String], env_clear: &[String]) -> String {
+    let mut lines = vec![format!("export {}={}", gpu_env, shell_quote(gpus))];
+    for s in env {
+        match s.split_once('=') {
+            Some((k, v)) if is_env_name(k) => {
+                lines.push(format!("export {}={}", k, shell_quote(v)));
+            }
+            _ => warn!("set_envs parse error: {}", s),
+        }
+    }
+    for s in env_clear {
+        if is_env_name(s) {
+            lines.push(format!("unset {}", s));
+        } else {
+            warn!("unset_envs parse error: {}", s);
+        }
+    }
+    lines.iter().map(|x| format!("{}\n", x)).collect()
+}
+
+/// The single source of the config defaults, also for the fields a config
+/// file leaves out.
+fn default_config() -> Config {
+    Config {
+        config_version: CONFIG_VERSION,
+        core_count: 1,
+        wait_for_indices: vec![],
+        allow_gpus: vec![],
+        deny_gpus: vec![],
+        pending_reservation: None,
+        memory_per_core: 1,
+        memory_percent: None,
+        memory_overhead: 0.0,
+        gpu_percent: 50,
+        check_times: 1,
+        check_interval: 15,
+        jitter: 0.0,
+        backoff_max: 0,
+        max_wait: 0,
+        gpu_env: "CUDA_VISIBLE_DEVICES".to_string(),
+        gpu_env_max_len: None,
+        gpu_env_max_count: None,
+        set_envs: vec![],
+        strict_env_expansion: false,
+        unset_envs: vec![],
+        memory_per_core_by_count: BTreeMap::new(),
+        min_driver_version: None,
+        strategy: Strategy::LeastUtil,
+        persistence_mode: PersistenceMode::Ignore,
+        backend: Backend::Nvidia,
+        #[cfg(feature = "nvml")]
+        nvml: false,
+        near_miss_percent: 10,
+        show_eta: false,
+        notify_command: None,
+        allow_self_stacking: true,
+        require_exclusive: false,
+        spread_pcie: false,
+        skip_ecc_errors: false,
+        max_total_power: None,
+        max_temp_delta: None,
+        temp_delta_polls: 3,
+        sample_count: 1,
+        sample_spacing: 100,
+        strict_gpu_count: false,
+        sort_output: false,
+        env_passthrough: vec![],
+        count_stable_polls: 1,
+        reserve_dir: None,
+        reserve_for: 0,
+        usage_ledger: None,
+        min_free_memory_percent: 0,
+        filter_fields: vec![],
+        memory_metric: MemoryMetric::Free,
+        selection_margin_percent: 0,
+        confirm_when_tight: false,
+        confirm_margin_percent: 10,
+        nvidia_smi_extra_args: vec![],
+        command_prefix: None,
+        shell: true,
+        start_banner: None,
+        stop_banner: None,
+        finally: None,
+        soak_command: None,
+        soak_cooldown: 600,
+        soak_failed: vec![],
+        secret_env_file: None,
+        cgroup: None,
+        nodes: vec![],
+        daemon_socket: None,
+        drain_file: None,
+        control_file: None,
+        yield_on_drain: false,
+        yield_on_file: None,
+        yield_on_foreign_process: false,
+        yield_grace: 10,
+        warn_memory_free: None,
+        warn_gpu_free: None,
+        presets: BTreeMap::new(),
+        schedule: vec![],
+        schedule_utc: false,
+        extends: None,
+    }
+}
+
+/// Config file names looked up in a directory given to `--config-path`, by precedence.
+const CONFIG_FILE_NAMES: [&str; 5] = [
+    "config.json",
+    "config.toml",
+    "config.yaml",
+    "config.yml",
+    ".plan.json",
+];
+
+fn discover_config(dir: &Path) -> PathBuf {
+    let found: Vec<PathBuf> = CONFIG_FILE_NAMES
+        .iter()
+        .map(|x| dir.join(x))
+        .filter(|x| x.is_file())
+        .collect();
+    match found.first() {
+        Some(v) => {
+            if found.len() > 1 {
+                info!(
+                    "Found {} config files in {}, using {}",
+                    found.len(),
+                    dir.display(),
+                    v.display()
+                );
+            }
+            v.clone()
+        }
+        None => {
+            error!(
+                "No config file in directory {}, searched in order: {}",
+                dir.display(),
+                CONFIG_FILE_NAMES.join(", ")
+            );
+            exit(1);
+        }
+    }
+}
+
+/// The config version this binary reads and writes. Bump it when the meaning
+/// of existing fields changes, with a migration in [`CONFIG_MIGRATIONS`].
+const CONFIG_VERSION: u64 = 1;
+
+/// The migrations of the fields of a config file to the current version, the
+/// one at index `i` from version `i + 1` to `i + 2`. Each is listed in the
+/// README.
+const CONFIG_MIGRATIONS: &[fn(&mut serde_json::Map<String, serde_json::Value>)] = &[];
+
+const _: () = assert!(CONFIG_MIGRATIONS.len() as u64 + 1 == CONFIG_VERSION);
+
+/// Bring the fields read from `path` to [`CONFIG_VERSION`], exiting if they
+/// are of a newer version than this binary knows.
+fn migrate_config_fields(path: &Path, fields: &mut serde_json::Map<String, serde_json::Value>) {
+    let version = match fields.get("config_version") {
+        None => 1,
+        Some(v) => match v.as_u64() {
+            Some(v) if v > 0 => v,
+            _ => {
+                error!(
+                    "config_version of {} isn't a positive integer: {}",
+                    path.display(),
+                    v
+                );
+                exit(1);
+            }
+        },
+    };
+    if version > CONFIG_VERSION {
+        error!(
+            "Config {} is of version {}, this binary only knows up to {}, update it",
+            path.display(),
+            version,
+            CONFIG_VERSION
+        );
+        exit(1);
+    }
+    for (i, migrate) in CONFIG_MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(version as usize - 1)
+    {
+        info!(
+            "Migrate config {} from version {} to {}",
+            path.display(),
+            i + 1,
+            i + 2
+        );
+        migrate(fields);
+    }
+    fields.insert("config_version".into(), CONFIG_VERSION.into());
+}
+
+/// The node config, set up by the admins for all users of the node.
+const NODE_CONFIG_PATH: &str = "/etc/gpu_wizard/node.json";
+/// Replaces [`NODE_CONFIG_PATH`], empty not to read a node config.
+const NODE_CONFIG_ENV: &str = "GPU_WIZARD_NODE_CONFIG";
+
+/// The config of the file at `file_path` overlaid onto the node config, if
+/// there is one, and the defaults.
+fn read_config_from_file(file_path: &Path, strict: bool) -> Config {
+    let mut fields = read_node_config_fields();
+    // an unknown existence is left to the read, which reports the error
+    if let Ok(false) = fs::exists(file_path) {
+        if strict {
+            error!("Config file doesn't exist: {}", file_path.display());
+            exit(1);
+        } else if fields.is_empty() {
+            return default_config();
+        }
+    } else {
+        fields.extend(read_config_fields(file_path, &mut vec![]));
+    }
+    let config: Config = match serde_json::from_value(serde_json::Value::Object(fields)) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Read config {} failed: {}", file_path.display(), e);
+            exit(1);
+        }
+    };
+    info!(
+        "Read config from {}:\n{}",
+        file_path.display(),
+        to_json_for_log(&config)
+    );
+    config
+}
+
+/// The fields of the node config, empty if there is none. It's optional, so
+/// only a path given in [`NODE_CONFIG_ENV`] is worth a warning when missing.
+fn read_node_config_fields() -> serde_json::Map<String, serde_json::Value> {
+    let (path, explicit) = match env::var_os(NODE_CONFIG_ENV) {
+        Some(v) => (PathBuf::from(v), true),
+        None => (PathBuf::from(NODE_CONFIG_PATH), false),
+    };
+    if path.as_os_str().is_empty() {
+        return serde_json::Map::new();
+    }
+    if !path.exists() {
+        if explicit {
+            warn!(
+                "Node config {} ({}) doesn't exist",
+                path.display(),
+                NODE_CONFIG_ENV
+            );
+        }
+        return serde_json::Map::new();
+    }
+    info!("Read node config from {}", path.display());
+    let mut fields = read_config_fields(&path, &mut vec![]);
+    // resolved already, and relative to the node config, not the user's
+    fields.remove("extends");
+    fields
+}
+
+/// The fields of the config file at `path`, overlaid onto the ones of the file
+/// it `extends`, recursively. `chain` holds the files extending it, to detect
+/// cycles. Each file is read in the format of its extension.
+fn read_config_fields(
+    path: &Path,
+    chain: &mut Vec<PathBuf>,
+) -> serde_json::Map<String, serde_json::Value> {
+    let content = fs::read_to_string(path).unwrap_or_else(|e| {
+        error!("Read config {} failed: {}", path.display(), e);
+        exit(1);
+    });
+    let format = ConfigFormat::from_path(path);
+    let mut fields = match format.parse(strip_config_content(&content)) {
+        Ok(serde_json::Value::Object(v)) => v,
+        Ok(_) => {
+            error!("Config {} isn't a {} object", path.display(), format);
+            exit(1);
+        }
+        Err(e) => {
+            error!("Read config {} failed: {}", path.display(), e);
+            exit(1);
+        }
+    };
+    // each file by its own version, before overlaying it onto its base
+    migrate_config_fields(path, &mut fields);
+    let Some(extends) = fields.get("extends").and_then(|x| x.as_str()) else {
+        return fields;
+    };
+    let base_path = path.parent().unwrap_or(Path::new("")).join(extends);
+    chain.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+    if fs::canonicalize(&base_path).is_ok_and(|x| chain.contains(&x)) {
+        error!(
+            "Config {} extends {}, which extends it in turn",
+            path.display(),
+            base_path.display()
+        );
+        exit(1);
+    }
+    let mut base = read_config_fields(&base_path, chain);
+    info!("Config {} extends {}", path.display(), base_path.display());
+    // the fields of the file itself win, including its own `extends`
+    base.extend(fields);
+    base
+}
+
+/// Override config fields from environment variables named after them with
+/// `prefix`, i.e. `WIZARD_CORE_COUNT=2` or `WIZARD_SET_ENVS=A=1,B=2`. A value is
+/// read as JSON if it parses as such, lists also as comma separated values, and
+/// otherwise as a string.
+fn apply_env_overrides(config: Config, prefix: &str) -> Config {
+    if prefix.is_empty() {
+        return config;
+    }
+    let serde_json::Value::Object(mut fields) = serde_json::to_value(&config).unwrap_or_default()
+    else {
+        return config;
+    };
+    let mut changed = false;
+    for (key, field) in fields.iter_mut() {
+        let name = format!("{}{}", prefix, key.to_uppercase());
+        let Ok(raw) = env::var(&name) else {
+            continue;
+        };
+        *field = match serde_json::from_str::<serde_json::Value>(&raw) {
+            Ok(v) if !field.is_array() || v.is_array() => v,
+            _ if field.is_array() => raw
+                .split(',')
+                .filter(|x| !x.is_empty())
+                .map(|x| serde_json::Value::from(x.trim()))
+                .collect(),
+            _ => serde_json::Value::from(raw),
+        };
+        info!("Config {} is set by {}", key, name);
+        changed = true;
+    }
+    if !changed {
+        return config;
+    }
+    match serde_json::from_value(serde_json::Value::Object(fields)) {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "Invalid config from {}* environment variables: {}",
+                prefix, e
+            );
+            exit(1);
+        }
+    }
+}
+
+/// Overlay the fields of the preset `name` onto `config`, exiting with the
+/// available presets if there is no such one.
+fn apply_preset(config: Config, name: &str) -> Config {
+    let Some(preset) = config.presets.get(name) else {
+        let names: Vec<&str> = config.presets.keys().map(String::as_str).collect();
+        error!(
+            "Unknown preset {}, available: {}",
+            name,
+            if names.is_empty() {
+                "none".to_string()
+            } else {
+                names.join(", ")
+            }
+        );
+        exit(1);
+    };
+    let preset = preset.clone();
+    let config = overlay_config(config, &preset, &format!("preset {}", name));
+    info!("Config preset {} is applied", name);
+    config
+}
+
+/// Overlay the entries of `schedule` whose window contains the current time
+/// onto `config`, later entries over earlier ones.
+fn apply_schedule(mut config: Config) -> Config {
+    if config.schedule.is_empty() {
+        return config;
+    }
+    let now = minutes_of_day(config.schedule_utc);
+    for entry in config.schedule.clone() {
+        let window = format!("{}-{}", entry.start, entry.end);
+        let (start, end) = match (
+            parse_time_of_day(&entry.start),
+            parse_time_of_day(&entry.end),
+        ) {
+            (Ok(start), Ok(end)) => (start, end),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Invalid schedule window {}: {}", window, e);
+                exit(1);
+            }
+        };
+        let within = if start <= end {
+            start <= now && now < end
+        } else {
+            now >= start || now < end
+        };
+        if within {
+            config = overlay_config(config, &entry.config, &format!("schedule {}", window));
+            info!(
+                "Config schedule {} is applied ({})",
+                window,
+                if config.schedule_utc {
+                    "UTC"
+                } else {
+                    "local time"
+                }
+            );
+        }
+    }
+    config
+}
+
+/// Set the fields of `overlay` in `config`, exiting if one doesn't exist or
+/// has the wrong type. `source` names the overlay in the errors.
+fn overlay_config(
+    config: Config,
+    overlay: &serde_json::Map<String, serde_json::Value>,
+    source: &str,
+) -> Config {
+    let serde_json::Value::Object(mut fields) = serde_json::to_value(&config).unwrap_or_default()
+    else {
+        return config;
+    };
+    for (key, value) in overlay {
+        if key == "presets" || key == "schedule" || !fields.contains_key(key) {
+            error!("Unknown config field {} in {}", key, source);
+            exit(1);
+        }
+        fields.insert(key.clone(), value.clone());
+    }
+    match serde_json::from_value(serde_json::Value::Object(fields)) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid config in {}: {}", source, e);
+            exit(1);
+        }
+    }
+}
+
+/// `HH:MM` as minutes since midnight.
+fn parse_time_of_day(s: &str) -> Result<u32, String> {
+    let (hours, minutes) = s
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| format!("expected HH:MM: {}", s))?;
+    let hours: u32 = hours.parse().map_err(|_| format!("invalid hours: {}", s))?;
+    let minutes: u32 = minutes
+        .parse()
+        .map_err(|_| format!("invalid minutes: {}", s))?;
+    // 24:00 ends a window at midnight
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        return Err(format!("out of range: {}", s));
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// The minutes since midnight now, in UTC or in the local time zone (`TZ`,
+/// otherwise the one of the system).
+fn minutes_of_day(utc: bool) -> u32 {
+    let now = unix_now();
+    if utc {
+        return (now % 86400 / 60) as u32;
+    }
+    #[repr(C)]
+    struct Tm {
+        tm_sec: i32,
+        tm_min: i32,
+        tm_hour: i32,
+        tm_mday: i32,
+        tm_mon: i32,
+        tm_year: i32,
+        tm_wday: i32,
+        tm_yday: i32,
+        tm_isdst: i32,
+        tm_gmtoff: i64,
+        tm_zone: *const std::ffi::c_char,
+    }
+    unsafe extern "C" {
+        fn localtime_r(time: *const i64, tm: *mut Tm) -> *mut Tm;
+    }
+    let time = now as i64;
+    // SAFETY: all-zero is a valid `Tm`, and localtime_r only writes to it
+    let mut tm: Tm = unsafe { std::mem::zeroed() };
+    if unsafe { localtime_r(&time, &mut tm) }.is_null() {
+        warn!("The local time is unknown, the schedule uses UTC");
+        return (now % 86400 / 60) as u32;
+    }
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+/// Merge the `set_envs` or `unset_envs` given on the command line into the
+/// ones of the config, where an entry replaces the config entry of the same
+/// variable and an empty one clears the entries before it.
+fn merge_envs(envs: &mut Vec<String>, cli_envs: Vec<String>) {
+    let name = |x: &str| x.split('=').next().unwrap_or("").to_string();
+    for v in cli_envs {
+        if v.is_empty() {
+            envs.clear();
+            continue;
+        }
+        let key = name(&v);
+        envs.retain(|x| name(x) != key);
+        envs.push(v);
+    }
+}
+
+/// The memory per core of the `memory_per_core_by_count` entry with the
+/// largest count not above `core_count`, `None` if there is no such entry.
+fn memory_for_core_count(config: &Config) -> Option<u32> {
+    config
+        .memory_per_core_by_count
+        .range(..=config.core_count)
+        .next_back()
+        .map(|(_, v)| *v)
+}
+
+/// Drop a leading UTF-8 BOM (written by some Windows editors) and surrounding whitespace.
+fn strip_config_content(content: &str) -> &str {
+    content.trim_start_matches('\u{feff}').trim()
+}
+
+/// Pretty JSON for output the user asked for, exiting with an error instead of
+/// panicking if `value` can't be serialized.
+fn to_json_or_exit<T: Serialize>(value: &T, what: &str) -> String {
+    match serde_json::to_string_pretty(value) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Serialize {} failed: {}", what, e);
+            exit(1);
+        }
+    }
+}
+
+/// Pretty JSON for log lines, where a serialization error isn't worth failing for.
+fn to_json_for_log<T: Serialize>(value: &T) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|e| format!("<serialize failed: {}>", e))
+}
+
+/// `value` in `format`, exiting with an error if it can't be serialized. JSON
+/// keeps the field order of `value`, the other formats sort the fields.
+fn to_config_format_or_exit<T: Serialize>(value: &T, format: ConfigFormat) -> String {
+    if format == ConfigFormat::Json {
+        return to_json_or_exit(value, "config");
+    }
+    serde_json::to_value(value)
+        .map_err(|e| e.to_string())
+        .and_then(|v| format.write(&v))
+        .unwrap_or_else(|e| {
+            error!("Serialize config failed: {}", e);
+            exit(1);
+        })
+}
+
+/// Write the config in the format of the extension of `file_path`, with
+/// `create_dirs` its missing parent directories are created first.
+fn save_config(config: &Config, file_path: &Path, create_dirs: bool) -> std::io::Result<()> {
+    let content = to_config_format_or_exit(config, ConfigFormat::from_path(file_path));
+    if create_dirs
+        && let Some(parent) = file_path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::create(file_path)?;
+    file.write_all(content.as_bytes())?;
+    file.flush()?;
+    info!("Config is saved to {}", file_path.display());
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LastSelection {
+    gpus: Vec<String>,
+}
+
+fn last_selection_path(config_path: &Path) -> PathBuf {
+    config_path.with_extension("last.json")
+}
+
+/// When the commands of this tool last used each GPU, by node and index, in
+/// seconds since the epoch.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct UsageLedger {
+    nodes: BTreeMap<String, BTreeMap<usize, u64>>,
+}
+
+fn usage_ledger_path(config_path: &Path) -> PathBuf {
+    config_path.with_extension("usage.json")
 }
 
-fn read_config_from_file(file_path: &Path, strict: bool) -> Config {
-    if !fs::exists(file_path).unwrap() {
-        if strict {
-            panic!("Config file isn't exist: {}", file_path.to_str().unwrap());
-        } else {
-            return default_config();
+fn read_usage_ledger(file_path: &Path) -> UsageLedger {
+    let content = match fs::read_to_string(file_path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return UsageLedger::default(),
+        Err(e) => {
+            warn!("Read usage ledger {} failed: {}", file_path.display(), e);
+            return UsageLedger::default();
+        }
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        warn!("Ignore invalid usage ledger {}: {}", file_path.display(), e);
+        UsageLedger::default()
+    })
+}
+
+/// Record `gpus` of `node` as used now.
+fn record_usage(file_path: &Path, node: &Node, gpus: &[usize]) {
+    let mut ledger = read_usage_ledger(file_path);
+    let now = unix_now();
+    let used = ledger.nodes.entry(node.to_string()).or_default();
+    for gpu in gpus {
+        used.insert(*gpu, now);
+    }
+    // replaced by a rename, so other instances never read a partial ledger
+    let tmp_path = file_path.with_extension(format!("{}.tmp", std::process::id()));
+    let result = fs::write(&tmp_path, to_json_or_exit(&ledger, "usage ledger"))
+        .and_then(|_| fs::rename(&tmp_path, file_path));
+    if let Err(e) = result {
+        warn!("Write usage ledger {} failed: {}", file_path.display(), e);
+        let _ = fs::remove_file(&tmp_path);
+    }
+}
+
+/// Fill `last_used` of each GPU from the usage ledger.
+fn attach_last_use(file_path: &Path, node: &Node, gpu_info_list: &mut [GPUInfo]) {
+    let ledger = read_usage_ledger(file_path);
+    let Some(used) = ledger.nodes.get(&node.to_string()) else {
+        return;
+    };
+    for gpu_info in gpu_info_list.iter_mut() {
+        gpu_info.last_used = used.get(&gpu_info.index).copied();
+    }
+}
+
+fn reuse_last_selection(file_path: &Path, config: &Config) -> Option<Vec<usize>> {
+    if !fs::exists(file_path).unwrap_or(false) {
+        info!("No last selection found at {}", file_path.display());
+        return None;
+    }
+    let content = fs::read_to_string(file_path).ok()?;
+    let last: LastSelection = match serde_json::from_str(&content) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!(
+                "Ignore invalid last selection {}: {}",
+                file_path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    if last.gpus.len() != config.core_count {
+        info!("Last selection {:?} doesn't match core_count", last.gpus);
+        return None;
+    }
+
+    let Ok(gpus) = last
+        .gpus
+        .iter()
+        .map(|x| x.parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()
+    else {
+        warn!("Ignore invalid last selection {:?}", last.gpus);
+        return None;
+    };
+
+    let gpu_info_list = match parse_cuda_info(config, &Node::Local) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Check last selection failed: {}", e);
+            return None;
         }
+    };
+    let reusable = gpus.iter().all(|gpu| {
+        gpu_info_list
+            .iter()
+            .any(|x| x.index == *gpu && is_gpu_available(x, config))
+    });
+    if reusable {
+        info!("Reuse last selection: {:?}", gpus);
+        Some(gpus)
     } else {
-        let mut file = fs::File::open(file_path).unwrap();
-        let mut content = String::new();
-        file.read_to_string(&mut content).unwrap();
-        let config: Config = serde_json::from_str(&content[..]).expect("Read config failed");
-        info!(
-            "Read config from {}:\n{}",
-            file_path.to_str().unwrap(),
-            serde_json::to_string_pretty(&config).unwrap()
+        info!("Last selection {:?} isn't available anymore.", last.gpus);
+        None
+    }
+}
+
+fn save_last_selection(gpus: &[usize], file_path: &Path) {
+    let last = LastSelection {
+        gpus: gpus.iter().map(|x| x.to_string()).collect(),
+    };
+    let result = serde_json::to_string_pretty(&last)
+        .map_err(std::io::Error::other)
+        .and_then(|x| fs::write(file_path, x));
+    if let Err(e) = result {
+        warn!(
+            "Save last selection to {} failed: {}",
+            file_path.display(),
+            e
         );
-        config
     }
 }
 
-fn save_config(config: &Config, file_path: &Path) {
-    let mut file = fs::File::create(file_path).unwrap();
-    file.write_all(serde_json::to_string_pretty(&config).unwrap().as_bytes())
-        .unwrap();
-    file.flush().unwrap();
-    info!("Config is saved to {}", file_path.to_str().unwrap());
+/// Parse a duration like `90`, `90s`, `5m`, `2h` or `1d` into seconds.
+fn parse_duration_secs(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let value = value
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let scale = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid duration unit: {}", s)),
+    };
+    Ok(value * scale)
+}
+
+/// One `--warn-threshold`.
+#[derive(Clone, Copy, Debug)]
+enum WarnThreshold {
+    /// GiB of free memory.
+    Memory(f64),
+    /// Percent of the GPU unused.
+    Gpu(usize),
+}
+
+/// Parse `memory=<GIB>` or `gpu=<PERCENT>`.
+fn parse_warn_threshold(s: &str) -> Result<WarnThreshold, String> {
+    let (kind, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected memory=<GIB> or gpu=<PERCENT>: {}", s))?;
+    let invalid = || format!("invalid value: {}", s);
+    match kind.trim() {
+        "memory" => value
+            .trim()
+            .parse()
+            .map(WarnThreshold::Memory)
+            .map_err(|_| invalid()),
+        "gpu" => value
+            .trim()
+            .parse()
+            .map(WarnThreshold::Gpu)
+            .map_err(|_| invalid()),
+        _ => Err(format!("unknown kind {}, expected memory or gpu", kind)),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// Whether a process with this pid exists.
+fn is_pid_alive(pid: u32) -> bool {
+    unsafe extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    const EPERM: i32 = 1;
+    // SAFETY: signal 0 only checks whether the process can be signaled.
+    if unsafe { kill(pid as i32, 0) } == 0 {
+        return true;
+    }
+    // the process exists but belongs to another user
+    std::io::Error::last_os_error().raw_os_error() == Some(EPERM)
+}
+
+/// The content of a `gpu<index>.lock` file in the reserve dir.
+#[derive(Serialize, Deserialize, Debug)]
+struct GPULock {
+    pid: u32,
+    user: String,
+    created: u64,
+    /// After the owner exits, the lock is kept for its user until this time.
+    #[serde(default)]
+    expires: Option<u64>,
+}
+
+impl GPULock {
+    fn path(dir: &Path, index: usize) -> PathBuf {
+        dir.join(format!("gpu{}.lock", index))
+    }
+
+    fn read(path: &Path) -> Option<GPULock> {
+        let content = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Whether the lock no longer protects anything and can be taken over.
+    fn is_stale(&self) -> bool {
+        !is_pid_alive(self.pid) && self.expires.is_none_or(|x| x <= unix_now())
+    }
+
+    /// Whether the current user may take over the lock, i.e. when restarting
+    /// within `--reserve-for` after the previous run exited.
+    fn is_reclaimable(&self) -> bool {
+        self.is_stale() || (!is_pid_alive(self.pid) && self.user == current_user())
+    }
+}
+
+/// Whether another instance holds the lock of this GPU.
+fn is_reserved(dir: &Path, index: usize) -> bool {
+    match GPULock::read(&GPULock::path(dir, index)) {
+        Some(lock) => lock.pid != std::process::id() && !lock.is_reclaimable(),
+        None => false,
+    }
+}
+
+fn try_lock(dir: &Path, index: usize) -> std::io::Result<bool> {
+    let path = GPULock::path(dir, index);
+    // one retry after removing a stale lock
+    for _ in 0..2 {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                let lock = GPULock {
+                    pid: std::process::id(),
+                    user: current_user().to_string(),
+                    created: unix_now(),
+                    expires: None,
+                };
+                let content = serde_json::to_string(&lock).map_err(std::io::Error::other)?;
+                file.write_all(content.as_bytes())?;
+                hold_lock(path);
+                return Ok(true);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                match GPULock::read(&path) {
+                    Some(lock) if !lock.is_reclaimable() => return Ok(false),
+                    // an unreadable lock may still be being written
+                    None => return Ok(false),
+                    Some(lock) => {
+                        info!("Reclaim lock of GPU {} from pid {}", index, lock.pid);
+                        fs::remove_file(&path)?;
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// The lock files this process holds, removed on exit if they weren't released
+/// or kept, i.e. on a signal before the command started.
+static HELD_LOCKS: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+
+fn hold_lock(path: PathBuf) {
+    unsafe extern "C" {
+        fn atexit(callback: extern "C" fn()) -> i32;
+    }
+    extern "C" fn on_exit() {
+        let held = std::mem::take(&mut *HELD_LOCKS.lock().unwrap_or_else(|e| e.into_inner()));
+        for path in held {
+            if GPULock::read(&path).is_some_and(|x| x.pid == std::process::id()) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+    static REGISTERED: std::sync::Once = std::sync::Once::new();
+
+    REGISTERED.call_once(|| {
+        // SAFETY: the callback doesn't unwind out of atexit, it ignores failures.
+        if unsafe { atexit(on_exit) } != 0 {
+            warn!("Register the lock cleanup failed, locks are left behind on exit");
+        }
+    });
+    HELD_LOCKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(path);
+}
+
+/// Take `paths` off the locks the exit cleanup removes.
+fn let_go_locks(paths: &[PathBuf]) {
+    HELD_LOCKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|x| !paths.contains(x));
+}
+
+/// The lock files held for the selected GPUs.
+struct Reservation {
+    dir: PathBuf,
+    gpus: Vec<usize>,
+}
+
+/// Lock every selected GPU when `reserve_dir` is set. All or nothing: if a GPU is
+/// already held by another instance, the taken locks are released and its index
+/// is returned.
+fn acquire_reservation(config: &Config, gpus: &[usize]) -> Result<Option<Reservation>, usize> {
+    let Some(dir) = &config.reserve_dir else {
+        return Ok(None);
+    };
+    if let Err(e) = fs::create_dir_all(dir) {
+        warn!("Create reserve dir {} failed: {}", dir.display(), e);
+    }
+    let mut reservation = Reservation {
+        dir: dir.clone(),
+        gpus: vec![],
+    };
+    for &index in gpus {
+        match try_lock(dir, index) {
+            Ok(true) => reservation.gpus.push(index),
+            Ok(false) => {
+                reservation.release();
+                return Err(index);
+            }
+            Err(e) => {
+                warn!("Lock GPU {} failed: {}", index, e);
+                reservation.release();
+                return Err(index);
+            }
+        }
+    }
+    info!("Reserved GPU {} in {}", join_gpus(gpus), dir.display());
+    Ok(Some(reservation))
+}
+
+impl Reservation {
+    fn paths(&self) -> Vec<PathBuf> {
+        self.gpus
+            .iter()
+            .map(|&x| GPULock::path(&self.dir, x))
+            .collect()
+    }
+
+    fn release(&self) {
+        let paths = self.paths();
+        for path in &paths {
+            if GPULock::read(path).is_some_and(|x| x.pid == std::process::id())
+                && let Err(e) = fs::remove_file(path)
+            {
+                warn!("Remove lock {} failed: {}", path.display(), e);
+            }
+        }
+        let_go_locks(&paths);
+    }
+
+    /// Release the locks, or keep them for `reserve_for` seconds so that a quick
+    /// restart by the same user gets the same GPUs.
+    fn finish(self, reserve_for: u64) {
+        if reserve_for == 0 {
+            self.release();
+            return;
+        }
+        let expires = unix_now() + reserve_for;
+        let paths = self.paths();
+        // kept on purpose, so the exit doesn't remove them
+        let_go_locks(&paths);
+        for path in paths {
+            if let Some(mut lock) = GPULock::read(&path)
+                && lock.pid == std::process::id()
+            {
+                lock.expires = Some(expires);
+                let result = serde_json::to_string(&lock)
+                    .map_err(std::io::Error::other)
+                    .and_then(|x| fs::write(&path, x));
+                if let Err(e) = result {
+                    warn!("Update lock {} failed: {}", path.display(), e);
+                }
+            }
+        }
+        info!("Keep the reservation for {} seconds", reserve_for);
+    }
+}
+
+/// All lock files in the reserve dir, by GPU index.
+fn read_locks(dir: &Path) -> Vec<(String, PathBuf, Option<GPULock>)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Read reserve dir {} failed: {}", dir.display(), e);
+            exit(1);
+        }
+    };
+    let mut locks = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(index) = name
+            .strip_prefix("gpu")
+            .and_then(|x| x.strip_suffix(".lock"))
+        {
+            locks.push((index.to_string(), path.clone(), GPULock::read(&path)));
+        }
+    }
+    locks.sort_by_key(|x| x.0.parse::<usize>().unwrap_or(usize::MAX));
+    locks
+}
+
+fn list_locks(dir: &Path) {
+    let now = unix_now();
+    println!(
+        "{:<4} {:>8} {:<12} {:>8} {:<6} EXPIRES",
+        "GPU", "PID", "USER", "AGE", "ALIVE"
+    );
+    for (index, path, lock) in read_locks(dir) {
+        match lock {
+            Some(lock) => {
+                let expires = match lock.expires {
+                    Some(v) if v > now => format!("in {}s", v - now),
+                    Some(_) => "expired".to_string(),
+                    None => "-".to_string(),
+                };
+                println!(
+                    "{:<4} {:>8} {:<12} {:>7}s {:<6} {}",
+                    index,
+                    lock.pid,
+                    lock.user,
+                    now.saturating_sub(lock.created),
+                    if is_pid_alive(lock.pid) { "yes" } else { "no" },
+                    expires
+                );
+            }
+            None => println!("{:<4} unreadable lock {}", index, path.display()),
+        }
+    }
+}
+
+fn clean_locks(dir: &Path, force: bool) {
+    for (index, path, lock) in read_locks(dir) {
+        let Some(lock) = lock else {
+            continue;
+        };
+        if lock.is_stale() || (force && !is_pid_alive(lock.pid)) {
+            match fs::remove_file(&path) {
+                Ok(_) => println!("Removed lock of GPU {} (pid {})", index, lock.pid),
+                Err(e) => warn!("Remove lock {} failed: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of `name` for the test, in the temp directory. It's
+    /// emptied by the next run rather than removed.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("gpu_wizard_test_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn gpu(index: usize, gpu_free: usize, memory_free: u32, memory_total: u32) -> GPUInfo {
+        GPUInfo {
+            index,
+            gpu_free,
+            memory_free,
+            memory_total,
+            ..Default::default()
+        }
+    }
+
+    /// A config with no thresholds but the ones set by the test.
+    fn open_config() -> Config {
+        Config {
+            memory_per_core: 0,
+            gpu_percent: 0,
+            ..default_config()
+        }
+    }
+
+    #[test]
+    fn parse_nounits_output() {
+        let output = "0, GPU-0, 20, 1800, 4096\n1, GPU-1, 0, 4000, 4096\n";
+        let gpus = parse_cuda_output(output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 0).unwrap();
+        assert_eq!(gpus.len(), 2);
+        assert_eq!(gpus[0].uuid, "GPU-0");
+        assert_eq!(gpus[0].gpu_free, 80);
+        assert_eq!(gpus[0].memory_free, 1800);
+        assert_eq!(gpus[0].memory_total, 4096);
+        assert_eq!(gpus[1].gpu_free, 100);
+    }
+
+    #[test]
+    fn parse_output_with_units() {
+        let output =
+            "0, GPU-0, 20 %, 1800 MiB, 4096 MiB, Enabled, 0, 41, 2296 MiB, 250.00 W, 61.20 W\n";
+        let gpus = parse_cuda_output(output, &QUERY_FIELDS, 0).unwrap();
+        assert_eq!(gpus[0].gpu_free, 80);
+        assert_eq!(gpus[0].memory_free, 1800);
+        assert_eq!(gpus[0].memory_total, 4096);
+        assert!(gpus[0].persistence_mode);
+        assert_eq!(gpus[0].ecc_errors, Some(0));
+        assert_eq!(gpus[0].temperature, Some(41));
+        assert_eq!(gpus[0].memory_used, Some(2296));
+    }
+
+    #[test]
+    fn parse_output_without_optional_values() {
+        let output = "0, GPU-0, 20, 1800, 4096, Disabled, [N/A], [N/A], 2296, [N/A], [N/A]\n";
+        let gpus = parse_cuda_output(output, &QUERY_FIELDS, 0).unwrap();
+        assert!(!gpus[0].persistence_mode);
+        assert_eq!(gpus[0].ecc_errors, None);
+        assert_eq!(gpus[0].temperature, None);
+        assert_eq!(gpus[0].power_limit, None);
+        assert_eq!(gpus[0].power_draw, None);
+    }
+
+    #[test]
+    fn parse_invalid_number_is_err() {
+        let output = "0, GPU-0, twenty, 1800, 4096\n";
+        assert!(parse_cuda_output(output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 0).is_err());
+    }
+
+    #[test]
+    fn read_bom_prefixed_json_config() {
+        let path = test_dir("bom").join("config.json");
+        fs::write(&path, "\u{feff}{\"core_count\": 3}\n\n").unwrap();
+        let fields = read_config_fields(&path, &mut vec![]);
+        assert_eq!(fields["core_count"], 3);
+    }
+
+    #[test]
+    fn strip_bom_and_whitespace_in_every_format() {
+        for (format, content) in [
+            (ConfigFormat::Json, "\u{feff}  {\"core_count\": 3}  \n"),
+            (ConfigFormat::Toml, "\u{feff}\ncore_count = 3\n\n"),
+            (ConfigFormat::Yaml, "\u{feff}core_count: 3\n  \n"),
+        ] {
+            let value = format.parse(strip_config_content(content)).unwrap();
+            assert_eq!(value["core_count"], 3, "{}", format);
+        }
+    }
+
+    #[test]
+    fn parse_shuffled_rows_in_index_order() {
+        let output = "2, GPU-2, 0, 4000, 4096\n0, GPU-0, 0, 4000, 4096\n1, GPU-1, 0, 4000, 4096\n";
+        let gpus = parse_cuda_output(output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 0).unwrap();
+        let indices: Vec<usize> = gpus.iter().map(|x| x.index).collect();
+        assert_eq!(indices, [0, 1, 2]);
+        assert_eq!(gpus[2].uuid, "GPU-2");
+    }
+
+    #[test]
+    fn select_from_shuffled_rows_by_index() {
+        let output = "3, GPU-3, 0, 4000, 4096\n1, GPU-1, 0, 4000, 4096\n2, GPU-2, 0, 4000, 4096\n";
+        let gpus = parse_cuda_output(output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 0).unwrap();
+        let config = Config {
+            core_count: 2,
+            ..default_config()
+        };
+        let selected = check_resource_enough(&gpus, &config, &HashMap::new());
+        assert_eq!(selected, Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn min_free_memory_percent_across_card_sizes() {
+        let config = Config {
+            min_free_memory_percent: 70,
+            ..open_config()
+        };
+        // 24 GiB, 80 GiB and 8 GiB cards
+        assert!(is_gpu_available(&gpu(0, 100, 18000, 24576), &config));
+        assert!(!is_gpu_available(&gpu(1, 100, 50000, 81920), &config));
+        assert!(is_gpu_available(&gpu(2, 100, 60000, 81920), &config));
+        assert!(!is_gpu_available(&gpu(3, 100, 5000, 8192), &config));
+        assert!(is_gpu_available(&gpu(4, 100, 5735, 8192), &config));
+    }
+
+    #[test]
+    fn min_free_memory_percent_with_memory_per_core() {
+        let config = Config {
+            min_free_memory_percent: 70,
+            memory_per_core: 20,
+            ..open_config()
+        };
+        // 73 % free, but less than 20 GiB
+        assert_eq!(
+            failed_criteria(&gpu(0, 100, 18000, 24576), &config),
+            [Criterion::Memory]
+        );
+        // 20 GiB free, but only 25 %
+        assert_eq!(
+            failed_criteria(&gpu(1, 100, 20480, 81920), &config),
+            [Criterion::MemoryPercent]
+        );
+        assert!(failed_criteria(&gpu(2, 100, 60000, 81920), &config).is_empty());
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from([&["gpu_wizard_execute"], args].concat()).unwrap()
+    }
+
+    #[test]
+    fn config_operations_without_command() {
+        for (args, expected) in [
+            (&["--print-config"][..], (true, false)),
+            (&["--save-config"], (false, true)),
+            (&["--print-config", "--save-config"], (true, true)),
+            (&["config"], (true, false)),
+            (&["config", "--save"], (false, true)),
+            (&["config", "--print", "--save"], (true, true)),
+        ] {
+            let cli = cli(args);
+            assert_eq!(config_operations(&cli), expected, "{:?}", args);
+            assert!(!has_execution(&cli), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn config_operations_with_command() {
+        let cli = cli(&["--print-config", "--save-config", "--", "true"]);
+        assert_eq!(config_operations(&cli), (true, true));
+        assert!(has_execution(&cli));
+        assert!(has_execution(&self::cli(&["--save-config", "--dry-run"])));
+        assert!(!has_execution(&self::cli(&[])));
+    }
+
+    #[test]
+    fn decode_invalid_utf8_output() {
+        let mut stdout = b"0, GPU-".to_vec();
+        stdout.extend_from_slice(&[0xff, 0xfe]);
+        stdout.extend_from_slice(b", 20, 1800, 4096\n");
+        let output = decode_output(stdout);
+        assert!(output.contains('\u{fffd}'));
+        let gpus = parse_cuda_output(&output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 0).unwrap();
+        assert_eq!(gpus[0].memory_free, 1800);
+    }
+
+    #[test]
+    fn parse_thousands_separated_values() {
+        let output = "0, GPU-0, 20 %, 40,960 MiB, 81,920 MiB\n1, GPU-1, 0, 1,024, 81,920\n";
+        let gpus = parse_cuda_output(output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 0).unwrap();
+        assert_eq!(gpus[0].gpu_free, 80);
+        assert_eq!(gpus[0].memory_free, 40960);
+        assert_eq!(gpus[0].memory_total, 81920);
+        assert_eq!(gpus[1].memory_free, 1024);
+        assert_eq!(gpus[1].memory_total, 81920);
+    }
+
+    #[test]
+    fn parse_quoted_thousands_separated_values() {
+        let output =
+            "0, GPU-0, 20 %, \"40,960 MiB\", \"81,920 MiB\"\n1, \"GPU-1\", 0, \"1,024\", 2048\n";
+        let gpus = parse_cuda_output(output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 0).unwrap();
+        assert_eq!(gpus[0].memory_free, 40960);
+        assert_eq!(gpus[0].memory_total, 81920);
+        assert_eq!(gpus[1].uuid, "GPU-1");
+        assert_eq!(gpus[1].memory_free, 1024);
+        assert_eq!(gpus[1].memory_total, 2048);
+    }
+
+    #[test]
+    fn parse_thousands_separated_values_with_filters() {
+        let output = "0, GPU-0, 20, 40,960, 81,920, 1,200\n";
+        let gpus = parse_cuda_output(output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 1).unwrap();
+        assert_eq!(gpus[0].memory_total, 81920);
+        assert_eq!(gpus[0].field_values, ["1,200"]);
+    }
+
+    #[test]
+    fn parse_more_fields_than_queried_is_err() {
+        let output = "0, GPU-0, 20, 1800, 4096, 7\n";
+        assert!(parse_cuda_output(output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 0).is_err());
+        assert!(parse_cuda_output(output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 1).is_ok());
+    }
+
+    #[test]
+    fn parse_three_digit_field_after_comma() {
+        // index 1 at 100 % utilization, not 1100
+        let output = "1, 100, 100, 1800, 4096\n";
+        let gpus = parse_cuda_output(output, &QUERY_FIELDS[..REQUIRED_QUERY_FIELDS], 0).unwrap();
+        assert_eq!(gpus[0].index, 1);
+        assert_eq!(gpus[0].uuid, "100");
+        assert_eq!(gpus[0].gpu_free, 0);
+    }
+
+    #[test]
+    fn partial_config_takes_defaults() {
+        let config: Config = serde_json::from_str(r#"{"core_count": 3}"#).unwrap();
+        assert_eq!(config.core_count, 3);
+        let mut expected = serde_json::to_value(default_config()).unwrap();
+        expected["core_count"] = 3.into();
+        assert_eq!(serde_json::to_value(&config).unwrap(), expected);
+    }
+
+    #[test]
+    fn empty_config_is_default() {
+        let config: Config = serde_json::from_str("{}").unwrap();
+        assert_eq!(
+            serde_json::to_value(&config).unwrap(),
+            serde_json::to_value(default_config()).unwrap()
+        );
+    }
+
+    #[test]
+    fn streak_of_the_same_gpus() {
+        let mut streak = SelectionStreak::default();
+        assert_eq!(streak.record(Some(&[0, 1])), 1);
+        // the same set in another order
+        assert_eq!(streak.record(Some(&[1, 0])), 2);
+        assert_eq!(streak.record(Some(&[0, 1])), 3);
+        assert_eq!(streak.record(None), 0);
+        assert_eq!(streak.record(Some(&[0, 1])), 1);
+    }
+
+    #[test]
+    fn streak_restarts_on_other_gpus() {
+        // GPU 0 free on the first check, GPU 1 on the second
+        let mut streak = SelectionStreak::default();
+        assert_eq!(streak.record(Some(&[0])), 1);
+        assert_eq!(streak.record(Some(&[1])), 1);
+        assert_eq!(streak.record(Some(&[1])), 2);
+    }
+
+    #[test]
+    fn streak_of_checks_of_changing_gpus() {
+        let config = Config {
+            check_times: 2,
+            ..open_config()
+        };
+        let checks = [
+            vec![gpu(0, 100, 4000, 4096), gpu(1, 0, 4000, 4096)],
+            vec![gpu(0, 0, 4000, 4096), gpu(1, 100, 4000, 4096)],
+        ];
+        let mut streak = SelectionStreak::default();
+        for gpus in &checks {
+            let selection = check_resource_enough(gpus, &config, &HashMap::new());
+            assert!(selection.is_some());
+            assert!(streak.record(selection.as_deref()) < config.check_times);
+        }
+    }
+
+    #[cfg(feature = "nvml")]
+    #[test]
+    fn nvml_reading_as_nvidia_smi_reports_it() {
+        let output = "0, GPU-0, 20, 1800, 4096, Enabled, 0, 41, 2296, 250.00, 61.20\n";
+        let expected = parse_cuda_output(output, &QUERY_FIELDS, 0).unwrap();
+        let mut gpu_info_list = vec![];
+        let mut parsed = 0;
+        let gpu_info = next_gpu_info(&mut gpu_info_list, &mut parsed, 0, "GPU-0");
+        fill_from_nvml(
+            gpu_info,
+            &nvml::Reading {
+                index: 0,
+                uuid: "GPU-0",
+                gpu_percent: 20,
+                memory_free: 1800 << 20,
+                memory_total: 4096 << 20,
+                memory_used: 2296 << 20,
+                persistence_mode: true,
+                ecc_errors: Some(0),
+                temperature: Some(41),
+                power_limit: Some(250.0),
+                power_draw: Some(61.2),
+            },
+        );
+        assert_eq!(
+            serde_json::to_value(&gpu_info_list).unwrap(),
+            serde_json::to_value(&expected).unwrap()
+        );
+    }
+
+    #[test]
+    fn free_memory_metric_keeps_memory_free() {
+        let mut gpus = vec![GPUInfo {
+            memory_used: Some(1000),
+            ..gpu(0, 100, 1800, 4096)
+        }];
+        apply_memory_metric(MemoryMetric::Free, &mut gpus);
+        assert_eq!(gpus[0].memory_free, 1800);
+    }
+
+    #[test]
+    fn computed_memory_metric_from_total_and_used() {
+        let mut gpus = vec![
+            GPUInfo {
+                memory_used: Some(1000),
+                ..gpu(0, 100, 1800, 4096)
+            },
+            // nothing to compute from
+            gpu(1, 100, 1800, 4096),
+        ];
+        apply_memory_metric(MemoryMetric::Computed, &mut gpus);
+        assert_eq!(gpus[0].memory_free, 3096);
+        assert_eq!(gpus[1].memory_free, 1800);
+    }
+
+    #[test]
+    fn memory_metric_decides_availability() {
+        // under MPS memory.free reports less than total - used
+        let reported = GPUInfo {
+            memory_used: Some(1024),
+            ..gpu(0, 100, 1024, 4096)
+        };
+        let config = Config {
+            memory_per_core: 2,
+            ..open_config()
+        };
+        let mut free = vec![reported.clone()];
+        apply_memory_metric(MemoryMetric::Free, &mut free);
+        assert!(!is_gpu_available(&free[0], &config));
+        let mut computed = vec![reported];
+        apply_memory_metric(MemoryMetric::Computed, &mut computed);
+        assert!(is_gpu_available(&computed[0], &config));
+    }
+
+    #[test]
+    fn memory_percent_per_card() {
+        let config = Config {
+            memory_percent: Some(40),
+            ..open_config()
+        };
+        // 40 % of 80 GiB is 32 GiB, of 24 GiB about 9.6 GiB
+        let large = gpu(0, 100, 40000, 81920);
+        let small = gpu(1, 100, 10000, 24576);
+        assert_eq!(memory_required_mib(&large, &config), 32768);
+        assert_eq!(memory_required_mib(&small, &config), 9830);
+        assert!(is_gpu_available(&large, &config));
+        assert!(is_gpu_available(&small, &config));
+        assert!(!is_gpu_available(&gpu(2, 100, 30000, 81920), &config));
+        assert!(!is_gpu_available(&gpu(3, 100, 9000, 24576), &config));
+    }
+
+    #[test]
+    fn memory_percent_conflicts_with_memory_per_core() {
+        let args = [
+            "gpu_wizard_execute",
+            "--memory-per-core",
+            "10",
+            "--memory-percent",
+            "40",
+        ];
+        let e = Cli::try_parse_from(args).unwrap_err();
+        assert_eq!(e.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    fn ranked(gpus: &[GPUInfo], strategy: Strategy) -> Vec<usize> {
+        let config = Config {
+            strategy,
+            ..open_config()
+        };
+        rank_gpus(gpus, &config, &HashMap::new())
+            .iter()
+            .map(|x| x.index)
+            .collect()
+    }
+
+    #[test]
+    fn rank_by_utilization_then_memory() {
+        let gpus = [
+            gpu(0, 90, 2000, 4096),
+            gpu(1, 90, 4000, 4096),
+            gpu(2, 100, 1000, 4096),
+            gpu(3, 90, 4000, 4096),
+        ];
+        assert_eq!(ranked(&gpus, Strategy::LeastUtil), [2, 1, 3, 0]);
+    }
+
+    #[test]
+    fn rank_by_memory_then_utilization() {
+        let gpus = [
+            gpu(0, 90, 2000, 4096),
+            gpu(1, 90, 4000, 4096),
+            gpu(2, 100, 1000, 4096),
+            gpu(3, 100, 4000, 4096),
+        ];
+        assert_eq!(ranked(&gpus, Strategy::MostFreeMemory), [3, 1, 0, 2]);
+    }
+
+    #[test]
+    fn rank_ties_by_index() {
+        let gpus: Vec<GPUInfo> = (0..4).map(|i| gpu(i, 100, 4000, 4096)).collect();
+        for strategy in [Strategy::LeastUtil, Strategy::MostFreeMemory] {
+            assert_eq!(ranked(&gpus, strategy), [0, 1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn select_by_aliases() {
+        let cli = cli(&["--select-by", "memory", "status"]);
+        assert_eq!(cli.selection.strategy, Some(Strategy::MostFreeMemory));
+        let cli = self::cli(&["--select-by", "util", "status"]);
+        assert_eq!(cli.selection.strategy, Some(Strategy::LeastUtil));
+    }
+
+    #[test]
+    fn interrupted_sleep_stops_early() {
+        let started = Instant::now();
+        let mut calls = 0;
+        let slept = sleep_in_steps(started, Duration::from_secs(10), || {
+            calls += 1;
+            calls > 2
+        });
+        assert!(!slept);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn sleep_counts_the_time_already_elapsed() {
+        // a sleep cut short, resumed with the same start, only sleeps the rest
+        let started = Instant::now() - Duration::from_millis(950);
+        let resumed = Instant::now();
+        assert!(sleep_in_steps(started, Duration::from_secs(1), || false));
+        assert!(resumed.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn check_interval_ends_with_max_wait() {
+        let config = Config {
+            check_interval: 15,
+            max_wait: 10,
+            ..open_config()
+        };
+        let started = Instant::now() - Duration::from_secs(8);
+        let interval = next_check_interval(&config, started, 0);
+        assert!(interval <= Duration::from_secs(2));
+        assert!(interval > Duration::from_secs(1));
+    }
+
+    #[test]
+    fn memory_overhead_on_small_card() {
+        // 3700 MiB free of an 8 GiB card, 1 GiB of which the driver keeps
+        let small = gpu(0, 100, 3700, 8192);
+        let config = Config {
+            min_free_memory_percent: 50,
+            ..open_config()
+        };
+        assert!(!is_gpu_available(&small, &config));
+        let config = Config {
+            memory_overhead: 1.0,
+            ..config
+        };
+        assert_eq!(usable_memory_mib(&small, &config), 7168);
+        assert!(is_gpu_available(&small, &config));
+    }
+
+    #[test]
+    fn memory_overhead_in_memory_percent() {
+        let small = gpu(0, 100, 3700, 8192);
+        let config = Config {
+            memory_percent: Some(50),
+            memory_overhead: 1.0,
+            ..open_config()
+        };
+        assert_eq!(memory_required_mib(&small, &config), 3584);
+        assert!(is_gpu_available(&small, &config));
+        // more than the total counts as nothing usable
+        let config = Config {
+            memory_overhead: 10.0,
+            ..config
+        };
+        assert_eq!(usable_memory_mib(&small, &config), 0);
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn blank_command_is_rejected() {
+        assert!(has_blank_command(&strings(&[" "]), &[]));
+        assert!(has_blank_command(&strings(&["", "\t"]), &[]));
+        assert!(has_blank_command(&[], &strings(&["train.sh", "  "])));
+    }
+
+    #[test]
+    fn command_with_words_is_accepted() {
+        assert!(!has_blank_command(&[], &[]));
+        assert!(!has_blank_command(&strings(&[" ", "true"]), &[]));
+        assert!(!has_blank_command(&[], &strings(&["train.sh", "eval.sh"])));
+    }
+
+    #[test]
+    fn control_file_pauses_and_resumes() {
+        let path = test_dir("control").join(".pause");
+        let config = Config {
+            control_file: Some(path.clone()),
+            ..open_config()
+        };
+        let mut paused = false;
+        assert!(!check_paused(&config, &mut paused));
+        fs::write(&path, "").unwrap();
+        assert!(check_paused(&config, &mut paused));
+        assert!(paused);
+        // stays paused while the file is there
+        assert!(check_paused(&config, &mut paused));
+        fs::remove_file(&path).unwrap();
+        assert!(!check_paused(&config, &mut paused));
+        assert!(!paused);
+    }
+
+    #[test]
+    fn no_control_file_never_pauses() {
+        let mut paused = false;
+        assert!(!check_paused(&open_config(), &mut paused));
+        assert!(!paused);
+    }
+
+    #[test]
+    fn save_config_into_missing_dir() {
+        let path = test_dir("save").join("missing").join("config.json");
+        let e = save_config(&default_config(), &path, false).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        assert!(!path.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn save_config_creates_missing_dirs() {
+        let path = test_dir("save_dirs")
+            .join("a")
+            .join("b")
+            .join("config.toml");
+        let config = Config {
+            core_count: 3,
+            ..default_config()
+        };
+        save_config(&config, &path, true).unwrap();
+        let fields = read_config_fields(&path, &mut vec![]);
+        assert_eq!(fields["core_count"], 3);
+    }
+
+    #[test]
+    fn bitmask_of_the_selection() {
+        assert_eq!(gpu_bitmask(&[1, 2], EXIT_BITMASK_GPUS), Some(6));
+        assert_eq!(gpu_bitmask(&[7, 0], EXIT_BITMASK_GPUS), Some(129));
+        assert_eq!(gpu_bitmask(&[8], EXIT_BITMASK_GPUS), None);
+        assert_eq!(
+            gpu_bitmask(&[8, 100], BITMASK_GPUS),
+            Some(1 << 8 | 1 << 100)
+        );
+        assert_eq!(gpu_bitmask(&[128], BITMASK_GPUS), None);
+    }
 }
@@ -0,0 +1,19 @@
+//! Desktop notifications through the notifier of the platform, with a
+//! terminal bell as the fallback.
+
+use log::{info, warn};
+
+/// Show a notification with `summary` and `body`, or ring the terminal bell
+/// and log them if no notification could be sent.
+pub fn send(summary: &str, body: &str) {
+    // D-Bus on Linux, the Notification Center on macOS
+    let result = notify_rust::Notification::new()
+        .appname(env!("CARGO_PKG_NAME"))
+        .summary(summary)
+        .body(body)
+        .show();
+    if let Err(e) = result {
+        info!("Desktop notification failed, ring the bell instead: {}", e);
+        warn!("\x07{}: {}", summary, body);
+    }
+}
@@ -0,0 +1,78 @@
+//! GPU readings through NVML, the library behind nvidia-smi. It's loaded at
+//! runtime, so the binary still runs where it's missing.
+
+use nvml_wrapper::enum_wrappers::device::{EccCounter, MemoryError, TemperatureSensor};
+use std::sync::OnceLock;
+
+/// The loaded and initialized library. It's never unloaded.
+pub struct Nvml {
+    nvml: nvml_wrapper::Nvml,
+}
+
+/// One GPU as nvidia-smi reports it with `--query-gpu`.
+pub struct Reading<'a> {
+    pub index: usize,
+    pub uuid: &'a str,
+    pub gpu_percent: usize,
+    /// In bytes.
+    pub memory_free: u64,
+    /// In bytes.
+    pub memory_total: u64,
+    /// In bytes.
+    pub memory_used: u64,
+    pub persistence_mode: bool,
+    pub ecc_errors: Option<u64>,
+    pub temperature: Option<u32>,
+    /// In watts.
+    pub power_limit: Option<f64>,
+    /// In watts.
+    pub power_draw: Option<f64>,
+}
+
+static SHARED: OnceLock<Result<Nvml, String>> = OnceLock::new();
+
+/// The library of this process, loaded and initialized on the first call.
+pub fn shared() -> &'static Result<Nvml, String> {
+    SHARED.get_or_init(|| {
+        nvml_wrapper::Nvml::init()
+            .map(|nvml| Nvml { nvml })
+            .map_err(|e| e.to_string())
+    })
+}
+
+impl Nvml {
+    /// Read all GPUs in index order, calling `f` with each. The optional
+    /// fields are `None` (or off) where the GPU doesn't support them.
+    pub fn read(&self, mut f: impl FnMut(Reading<'_>)) -> Result<(), String> {
+        let count = self.nvml.device_count().map_err(|e| e.to_string())?;
+        for index in 0..count {
+            let device = self
+                .nvml
+                .device_by_index(index)
+                .map_err(|e| e.to_string())?;
+            let utilization = device.utilization_rates().map_err(|e| e.to_string())?;
+            let memory = device.memory_info().map_err(|e| e.to_string())?;
+            let uuid = device.uuid().map_err(|e| e.to_string())?;
+            f(Reading {
+                index: index as usize,
+                uuid: &uuid,
+                gpu_percent: utilization.gpu as usize,
+                memory_free: memory.free,
+                memory_total: memory.total,
+                memory_used: memory.used,
+                persistence_mode: device.is_in_persistent_mode().unwrap_or(false),
+                ecc_errors: device
+                    .total_ecc_errors(MemoryError::Uncorrected, EccCounter::Aggregate)
+                    .ok(),
+                temperature: device.temperature(TemperatureSensor::Gpu).ok(),
+                // both in milliwatts
+                power_limit: device
+                    .enforced_power_limit()
+                    .ok()
+                    .map(|x| x as f64 / 1000.0),
+                power_draw: device.power_usage().ok().map(|x| x as f64 / 1000.0),
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Minimal OpenTelemetry trace export over OTLP/HTTP (JSON encoding).
+
+use log::{info, warn};
+use serde_json::{Value, json};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub struct Tracer {
+    host: String,
+    path: String,
+    trace_id: String,
+}
+
+pub struct Span {
+    name: &'static str,
+    span_id: String,
+    parent_span_id: String,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<Value>,
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut out = String::new();
+    while out.len() < bytes * 2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        out.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    out.truncate(bytes * 2);
+    out
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+impl Tracer {
+    /// `endpoint` is the OTLP/HTTP collector, i.e. `http://localhost:4318`.
+    pub fn new(endpoint: &str) -> Tracer {
+        let rest = match endpoint.strip_prefix("http://") {
+            Some(v) => v,
+            None => {
+                warn!("otel: only http:// endpoints are supported: {}", endpoint);
+                endpoint.split("://").last().unwrap_or(endpoint)
+            }
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) if rest[i..].len() > 1 => (&rest[..i], rest[i..].to_string()),
+            Some(i) => (&rest[..i], "/v1/traces".to_string()),
+            None => (rest, "/v1/traces".to_string()),
+        };
+        Tracer {
+            host: host.to_string(),
+            path,
+            trace_id: random_hex(16),
+        }
+    }
+
+    pub fn span(&self, name: &'static str, parent: Option<&Span>) -> Span {
+        Span {
+            name,
+            span_id: random_hex(8),
+            parent_span_id: parent.map(|x| x.span_id.clone()).unwrap_or_default(),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: vec![],
+        }
+    }
+
+    /// W3C trace context for the given span, passed to the command as `TRACEPARENT`.
+    pub fn traceparent(&self, span: &Span) -> String {
+        format!("00-{}-{}-01", self.trace_id, span.span_id)
+    }
+
+    pub fn export(&self, spans: &[&Span]) {
+        let spans: Vec<Value> = spans
+            .iter()
+            .map(|x| {
+                json!({
+                    "traceId": self.trace_id,
+                    "spanId": x.span_id,
+                    "parentSpanId": x.parent_span_id,
+                    "name": x.name,
+                    "kind": 1,
+                    "startTimeUnixNano": unix_nanos(x.start),
+                    "endTimeUnixNano": unix_nanos(x.end),
+                    "attributes": x.attributes,
+                })
+            })
+            .collect();
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {"attributes": [
+                    {"key": "service.name", "value": {"stringValue": env!("CARGO_PKG_NAME")}}
+                ]},
+                "scopeSpans": [{
+                    "scope": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+                    "spans": spans,
+                }],
+            }],
+        })
+        .to_string();
+        match self.post(&body) {
+            Ok(status) => info!("otel: exported {} span(s): {}", spans.len(), status),
+            Err(e) => warn!("otel: export to {} failed: {}", self.host, e),
+        }
+    }
+
+    fn post(&self, body: &str) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(&self.host)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response.lines().next().unwrap_or("").to_string())
+    }
+}
+
+impl Span {
+    pub fn attr_str(&mut self, key: &str, value: &str) {
+        self.attributes
+            .push(json!({"key": key, "value": {"stringValue": value}}));
+    }
+
+    pub fn attr_int(&mut self, key: &str, value: i64) {
+        self.attributes
+            .push(json!({"key": key, "value": {"intValue": value.to_string()}}));
+    }
+
+    pub fn end(&mut self) {
+        self.end = SystemTime::now();
+    }
+}